@@ -0,0 +1,30 @@
+//! Times `get` hits on a cache without any expiry, which skips reading the expiry
+//! settings, next to one with a TTL. Run with `cargo bench --bench get`.
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use cream::Cache;
+
+fn main() {
+    let reads = 1_000_000;
+    for ttl in [None, Some(Duration::from_secs(3600))] {
+        let mut cache = Cache::<usize, usize>::new();
+        if let Some(ttl) = ttl {
+            cache = cache.with_ttl(ttl);
+        }
+        for i in 0..1_000 {
+            cache.put(i, i);
+        }
+        let start = Instant::now();
+        for i in 0..reads {
+            black_box(cache.get(&(i % 1_000)));
+        }
+        println!(
+            "ttl {:>9}: {:?} per get",
+            format!("{ttl:?}"),
+            start.elapsed() / reads as u32
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! Times overwrites of a small set of hot keys from several threads, with and without
+//! insertion order tracking. Run with `cargo bench --bench order_tracking`.
+use std::{thread, time::Instant};
+
+use cream::Cache;
+
+fn main() {
+    let threads = 4;
+    let writes = 200_000;
+    for track in [true, false] {
+        let cache = Cache::<usize, usize>::new()
+            .with_order_tracking(track)
+            .into_arc();
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..writes {
+                        cache.put(i % 64, t);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        println!(
+            "order tracking {track:>5}: {:?} per put",
+            start.elapsed() / (threads * writes) as u32
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! Times `remove` on caches of growing size, the cost per removal should stay flat.
+//! Run with `cargo bench --bench remove`.
+use std::time::Instant;
+
+use cream::Cache;
+
+fn main() {
+    for size in [1_000, 10_000, 100_000] {
+        let cache: Cache<usize, usize> = Cache::new();
+        for i in 0..size {
+            cache.put(i, i);
+        }
+        let removals = 1_000;
+        let start = Instant::now();
+        for i in (0..size).step_by(size / removals) {
+            cache.remove(&i);
+        }
+        println!(
+            "size {size:>7}: {:?} per remove",
+            start.elapsed() / removals as u32
+        );
+    }
+}
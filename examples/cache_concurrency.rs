@@ -5,11 +5,10 @@ use std::time::Duration;
 
 fn main() {
     // Create cache with max 10 keys, default 2s TTL, 50ms cleanup interval
-    let cache: Arc<Cache<i32, String>> = Arc::new(
-        Cache::new()
-            .with_max_size(10)
-            .with_ttl(Duration::from_secs(2)),
-    );
+    let cache: Arc<Cache<i32, String>> = Cache::new()
+        .with_max_size(10)
+        .with_ttl(Duration::from_secs(2))
+        .into_arc();
 
     // Insert initial data
     cache.put(1, "one".to_string());
@@ -0,0 +1,117 @@
+use std::{
+    io::{self, Read, Write},
+    marker::PhantomData,
+};
+
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::Cache;
+
+/// The algorithm a [`CompressedCache`] compresses values with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Deflate at the default compression level
+    Deflate,
+}
+
+impl Codec {
+    fn encode(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Deflate => {
+                let mut decoded = Vec::new();
+                DeflateDecoder::new(bytes).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+        }
+    }
+}
+
+/// A cache that transparently stores its values serialized and compressed.
+/// Created with [`Cache::with_compression`] on a byte cache, which keeps its
+/// max size, time-to-live and other configuration.
+///
+/// This trades CPU for memory: every `put` serializes and compresses the value and
+/// every `get` decompresses and deserializes it, so it only pays off for large,
+/// compressible values such as serialized payloads.
+pub struct CompressedCache<K, V> {
+    inner: Cache<K, Vec<u8>>,
+    codec: Codec,
+    _marker: PhantomData<fn() -> V>,
+}
+
+impl<K: Ord + Clone + Sync + Send + 'static> Cache<K, Vec<u8>> {
+    /// Turns this byte cache into a [`CompressedCache`] of `V` values compressed with `codec`.
+    pub fn with_compression<V>(self, codec: Codec) -> CompressedCache<K, V>
+    where
+        V: Serialize + DeserializeOwned,
+    {
+        CompressedCache {
+            inner: self,
+            codec,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Ord + Clone + Sync + Send + 'static, V: Serialize + DeserializeOwned>
+    CompressedCache<K, V>
+{
+    /// Serializes and compresses the value, then puts it into the cache for the given key.
+    pub fn put(&self, key: K, val: &V) -> io::Result<()> {
+        let bytes = self.codec.encode(&serde_json::to_vec(val)?)?;
+        self.inner.put(key, bytes);
+        Ok(())
+    }
+
+    /// Gets the current value for the given key, decompressed and deserialized.
+    /// Returns Ok(None) if the key does not exist or is past its time-to-live.
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        match self.inner.get(key) {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&self.codec.decode(&bytes)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks for the presence of a key without decompressing its value.
+    pub fn exists(&self, key: &K) -> bool {
+        self.inner.exists(key)
+    }
+
+    /// Remove a key from the cache. Returns true if the key was in the cache.
+    pub fn remove(&self, key: &K) -> bool {
+        self.inner.remove(key).is_some()
+    }
+
+    /// The underlying cache holding the compressed bytes.
+    pub fn inner(&self) -> &Cache<K, Vec<u8>> {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod compress_tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_cache_round_trip() {
+        let cache: CompressedCache<String, String> = Cache::new().with_compression(Codec::Deflate);
+        let value = "cache rules everything around me ".repeat(1000);
+        cache.put("hello1".into(), &value).unwrap();
+
+        assert_eq!(cache.get(&"hello1".into()).unwrap(), Some(value.clone()));
+        let stored = cache.inner().get("hello1").unwrap();
+        assert!(stored.len() < value.len());
+        assert_eq!(cache.get(&"hello2".into()).unwrap(), None);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+};
+
+use crate::Cache;
+
+/// An ordering for the keys of a [`ComparatorCache`], used instead of the keys' own `Ord`,
+/// e.g. when that one is expensive or sorts the wrong way round.
+pub trait Comparator<K> {
+    fn compare(a: &K, b: &K) -> Ordering;
+}
+
+/// Orders keys from the largest to the smallest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Descending;
+
+impl<K: Ord> Comparator<K> for Descending {
+    fn compare(a: &K, b: &K) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// A key as stored by a [`ComparatorCache`], ordered with `C`.
+pub struct ComparedKey<K, C> {
+    key: K,
+    _comparator: PhantomData<fn() -> C>,
+}
+
+impl<K, C> ComparedKey<K, C> {
+    fn new(key: K) -> Self {
+        ComparedKey {
+            key,
+            _comparator: PhantomData,
+        }
+    }
+}
+
+impl<K: Clone, C> Clone for ComparedKey<K, C> {
+    fn clone(&self) -> Self {
+        ComparedKey::new(self.key.clone())
+    }
+}
+
+impl<K, C: Comparator<K>> PartialEq for ComparedKey<K, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K, C: Comparator<K>> Eq for ComparedKey<K, C> {}
+
+impl<K, C: Comparator<K>> PartialOrd for ComparedKey<K, C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, C: Comparator<K>> Ord for ComparedKey<K, C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::compare(&self.key, &other.key)
+    }
+}
+
+/// A key looked up in a [`ComparatorCache`]: either a stored [`ComparedKey`] or a plain
+/// borrowed key, so lookups don't have to clone the key to wrap it.
+pub trait ComparedLookup<K, C> {
+    fn key(&self) -> &K;
+}
+
+impl<K, C> ComparedLookup<K, C> for K {
+    fn key(&self) -> &K {
+        self
+    }
+}
+
+impl<K, C> ComparedLookup<K, C> for ComparedKey<K, C> {
+    fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<'a, K: 'a, C: 'a> Borrow<dyn ComparedLookup<K, C> + 'a> for ComparedKey<K, C> {
+    fn borrow(&self) -> &(dyn ComparedLookup<K, C> + 'a) {
+        self
+    }
+}
+
+impl<K, C: Comparator<K>> PartialEq for dyn ComparedLookup<K, C> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K, C: Comparator<K>> Eq for dyn ComparedLookup<K, C> + '_ {}
+
+impl<K, C: Comparator<K>> PartialOrd for dyn ComparedLookup<K, C> + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, C: Comparator<K>> Ord for dyn ComparedLookup<K, C> + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::compare(self.key(), other.key())
+    }
+}
+
+/// A cache whose keys are kept in the order given by the comparator `C` rather than
+/// by their `Ord`. Created with [`Cache::with_comparator`], which keeps the cache's
+/// max size, time-to-live and other configuration.
+pub struct ComparatorCache<K, V, C> {
+    inner: Cache<ComparedKey<K, C>, V>,
+}
+
+impl<K: Clone, V: Clone, C: Comparator<K>> Cache<ComparedKey<K, C>, V> {
+    /// Turns this cache into a [`ComparatorCache`] ordering its keys with `C`.
+    pub fn with_comparator(self) -> ComparatorCache<K, V, C> {
+        ComparatorCache { inner: self }
+    }
+}
+
+impl<K: Clone, V: Clone, C: Comparator<K>> ComparatorCache<K, V, C> {
+    fn lookup(key: &K) -> &dyn ComparedLookup<K, C> {
+        key
+    }
+
+    /// Puts a value into the cache, returning the previous one for the key if any.
+    pub fn put(&self, key: K, val: V) -> Option<V> {
+        self.inner.put(ComparedKey::new(key), val)
+    }
+
+    /// Gets the current value for the given key. Returns None if the key does not
+    /// exist or is past its time-to-live.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.inner.get(Self::lookup(key))
+    }
+
+    /// Whether the key is in the cache and not expired.
+    pub fn exists(&self, key: &K) -> bool {
+        self.inner.exists(Self::lookup(key))
+    }
+
+    /// Removes a key from the cache, returning its value if it was there.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.inner.remove(Self::lookup(key))
+    }
+
+    /// The live keys, in the comparator's order.
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = K> {
+        self.inner.keys().map(|k| k.key)
+    }
+
+    /// Returns the live entry with the smallest key according to the comparator.
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        self.inner.first_key_value().map(|(k, v)| (k.key, v))
+    }
+
+    /// Returns the live entry with the largest key according to the comparator.
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        self.inner.last_key_value().map(|(k, v)| (k.key, v))
+    }
+
+    /// The underlying cache holding the wrapped keys.
+    pub fn inner(&self) -> &Cache<ComparedKey<K, C>, V> {
+        &self.inner
+    }
+}
+
+/// A key as stored by a [`HashedCache`], along with its hash.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HashedKey<K> {
+    hash: u64,
+    key: K,
+}
+
+/// A key looked up in a [`HashedCache`]: either a stored [`HashedKey`] or a borrowed key
+/// with its hash, so lookups don't have to clone the key to wrap it.
+pub trait HashedLookup<K> {
+    fn hash(&self) -> u64;
+    fn key(&self) -> &K;
+}
+
+impl<K> HashedLookup<K> for HashedKey<K> {
+    fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K> HashedLookup<K> for (u64, &K) {
+    fn hash(&self) -> u64 {
+        self.0
+    }
+
+    fn key(&self) -> &K {
+        self.1
+    }
+}
+
+impl<'a, K: 'a> Borrow<dyn HashedLookup<K> + 'a> for HashedKey<K> {
+    fn borrow(&self) -> &(dyn HashedLookup<K> + 'a) {
+        self
+    }
+}
+
+impl<K: Ord> PartialEq for dyn HashedLookup<K> + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for dyn HashedLookup<K> + '_ {}
+
+impl<K: Ord> PartialOrd for dyn HashedLookup<K> + '_ {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The same order as `HashedKey`'s derived one: by hash, then by key.
+impl<K: Ord> Ord for dyn HashedLookup<K> + '_ {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.hash(), self.key()).cmp(&(other.hash(), other.key()))
+    }
+}
+
+/// A cache that orders its keys by their hash first, so a lookup only compares whole
+/// keys when their hashes are equal, e.g. for long string keys sharing prefixes.
+/// Created with [`Cache::with_hasher`], which keeps the cache's max size,
+/// time-to-live and other configuration. Key order follows the hashes, so it looks random.
+pub struct HashedCache<K, V, S> {
+    inner: Cache<HashedKey<K>, V>,
+    hasher: S,
+}
+
+impl<K: Ord + Clone, V: Clone> Cache<HashedKey<K>, V> {
+    /// Turns this cache into a [`HashedCache`] hashing its keys with `hasher`.
+    pub fn with_hasher<S: BuildHasher>(self, hasher: S) -> HashedCache<K, V, S> {
+        HashedCache {
+            inner: self,
+            hasher,
+        }
+    }
+}
+
+impl<K: Ord + Clone + Hash, V: Clone, S: BuildHasher> HashedCache<K, V, S> {
+    fn hashed(&self, key: K) -> HashedKey<K> {
+        HashedKey {
+            hash: self.hasher.hash_one(&key),
+            key,
+        }
+    }
+
+    /// Puts a value into the cache, returning the previous one for the key if any.
+    pub fn put(&self, key: K, val: V) -> Option<V> {
+        self.inner.put(self.hashed(key), val)
+    }
+
+    /// Gets the current value for the given key. Returns None if the key does not
+    /// exist or is past its time-to-live.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.inner
+            .get(&(self.hasher.hash_one(key), key) as &dyn HashedLookup<K>)
+    }
+
+    /// Whether the key is in the cache and not expired.
+    pub fn exists(&self, key: &K) -> bool {
+        self.inner
+            .exists(&(self.hasher.hash_one(key), key) as &dyn HashedLookup<K>)
+    }
+
+    /// Removes a key from the cache, returning its value if it was there.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.inner
+            .remove(&(self.hasher.hash_one(key), key) as &dyn HashedLookup<K>)
+    }
+
+    /// The underlying cache holding the hashed keys.
+    pub fn inner(&self) -> &Cache<HashedKey<K>, V> {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod keyed_tests {
+    use std::{
+        hash::RandomState,
+        sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_cache_with_comparator() {
+        let cache: ComparatorCache<i32, i32, Descending> =
+            Cache::new().with_max_size(3).with_comparator();
+        for i in [2, 5, 1] {
+            cache.put(i, i * 10);
+        }
+        assert_eq!(cache.first_key_value(), Some((5, 50)));
+        assert_eq!(cache.last_key_value(), Some((1, 10)));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec![5, 2, 1]);
+        assert_eq!(cache.get(&2), Some(20));
+
+        // The inner cache's configuration still applies
+        cache.put(7, 70);
+        assert!(!cache.exists(&2));
+        assert_eq!(cache.remove(&7), Some(70));
+        assert_eq!(cache.inner().len(), 2);
+    }
+
+    #[test]
+    fn test_cache_with_hasher() {
+        let cache: HashedCache<String, i32, _> = Cache::new().with_hasher(RandomState::new());
+        let prefix = "hello".repeat(100);
+        cache.put(format!("{prefix}1"), 5);
+        cache.put(format!("{prefix}2"), 6);
+        assert_eq!(cache.get(&format!("{prefix}1")), Some(5));
+        assert!(cache.exists(&format!("{prefix}2")));
+        assert_eq!(cache.remove(&format!("{prefix}2")), Some(6));
+        assert_eq!(cache.get(&format!("{prefix}2")), None);
+        assert_eq!(cache.inner().len(), 1);
+    }
+
+    /// A key that counts how often it's cloned.
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+    struct CountedKey(String);
+
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+    impl Clone for CountedKey {
+        fn clone(&self) -> Self {
+            CLONES.fetch_add(1, AtomicOrdering::Relaxed);
+            CountedKey(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_cache_keyed_lookups_dont_clone() {
+        let compared: ComparatorCache<CountedKey, i32, Descending> = Cache::new().with_comparator();
+        let hashed: HashedCache<CountedKey, i32, _> = Cache::new().with_hasher(RandomState::new());
+        compared.put(CountedKey("hello1".into()), 5);
+        hashed.put(CountedKey("hello1".into()), 5);
+
+        let key = CountedKey("hello1".into());
+        let before = CLONES.load(AtomicOrdering::Relaxed);
+        assert_eq!(compared.get(&key), Some(5));
+        assert!(compared.exists(&key));
+        assert_eq!(hashed.get(&key), Some(5));
+        assert!(hashed.exists(&key));
+        assert_eq!(compared.remove(&key), Some(5));
+        assert_eq!(hashed.remove(&key), Some(5));
+        assert_eq!(CLONES.load(AtomicOrdering::Relaxed), before);
+    }
+}
@@ -1,300 +1,5297 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
-    sync::{Arc, Mutex, RwLock},
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    marker::PhantomData,
+    ops::{Deref, DerefMut, RangeBounds},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Condvar, LockResult, Mutex, OnceLock, PoisonError, RwLock, TryLockError,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+    },
     thread::{self, JoinHandle},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
+#[cfg(feature = "compress")]
+mod compress;
+#[cfg(feature = "compress")]
+pub use compress::{Codec, CompressedCache};
+mod keyed;
+mod lock;
+#[cfg(feature = "tokio")]
+mod stream;
+mod view;
+
+pub use keyed::{Comparator, ComparatorCache, ComparedKey, Descending, HashedCache, HashedKey};
+pub use view::CacheView;
+
+use lock::{CacheLock, CacheReadGuard, CacheWriteGuard};
+
+/// Callback used to persist dirty entries to a backing store.
+type FlushFn<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+
+/// Observer called with an entry on an insert or a hit, see `with_on_insert` and `with_on_hit`.
+type ObserverFn<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+
+/// Check every written value must pass, see `with_value_validator`.
+type ValidatorFn<V> = Arc<dyn Fn(&V) -> bool + Send + Sync>;
+
+/// Hands an expired entry to an `expired_receiver` channel, returning false once it's closed.
+type ExpiredSender<K, V> = Box<dyn Fn(&K, &V) -> bool + Send>;
+type ExpiredSenders<K, V> = Arc<Mutex<Vec<ExpiredSender<K, V>>>>;
+
+/// A single value in the cache along with the bookkeeping kept for it.
+struct Entry<V> {
+    value: V,
+    /// When the entry was written, on the cache's clock
+    written: i64,
+    dirty: bool,
+    /// Position of the key in the cache's insert order
+    seq: u64,
+    /// Number of reads that found this entry, bumped under the read lock
+    accesses: AtomicU64,
+    /// Overrides the cache-wide time-to-live for this entry
+    ttl: Option<Duration>,
+    /// When the entry was last read or written, on the cache's clock
+    last_access: AtomicI64,
+    /// Version given with `put_versioned`, 0 for plain writes
+    version: u64,
+    /// Set by reads, cleared when the clock policy gives the entry a second chance
+    referenced: AtomicBool,
+    /// Wall-clock deadline given with `put_until`
+    until: Option<SystemTime>,
+}
+
+impl<V: Clone> Clone for Entry<V> {
+    fn clone(&self) -> Self {
+        Entry {
+            value: self.value.clone(),
+            written: self.written,
+            dirty: self.dirty,
+            seq: self.seq,
+            accesses: AtomicU64::new(self.accesses.load(Ordering::Relaxed)),
+            ttl: self.ttl,
+            last_access: AtomicI64::new(self.last_access.load(Ordering::Relaxed)),
+            version: self.version,
+            referenced: AtomicBool::new(self.referenced.load(Ordering::Relaxed)),
+            until: self.until,
+        }
+    }
+}
+
+impl<V> Entry<V> {
+    fn new(value: V, seq: u64, written: i64) -> Self {
+        Entry {
+            value,
+            written,
+            dirty: true,
+            seq,
+            accesses: AtomicU64::new(0),
+            ttl: None,
+            last_access: AtomicI64::new(written),
+            version: 0,
+            referenced: AtomicBool::new(false),
+            until: None,
+        }
+    }
+
+    fn touch(&self, clock: &EntryClock) {
+        self.accesses.fetch_add(1, Ordering::Relaxed);
+        self.referenced.store(true, Ordering::Relaxed);
+        self.last_access.store(clock.now().ticks, Ordering::Relaxed);
+    }
+
+    /// Whether the entry is still within its own time-to-live, or else the cache-wide
+    /// one, and has not been idle for longer than the cache allows.
+    fn is_live(&self, live: Liveness) -> bool {
+        let now = live.now;
+        self.ttl
+            .or(live.expiry.ttl)
+            .is_none_or(|ttl| now.within(self.written, ttl))
+            && self.until.is_none_or(|until| SystemTime::now() < until)
+            && live
+                .expiry
+                .idle
+                .is_none_or(|idle| now.within(self.last_access.load(Ordering::Relaxed), idle))
+            && live
+                .expiry
+                .max_age
+                .is_none_or(|age| now.within(self.written, age))
+    }
+
+    /// How long until the entry expires, or None if it never does.
+    fn remaining(&self, live: Liveness) -> Option<Duration> {
+        let ttl = match (self.ttl.or(live.expiry.ttl), live.expiry.max_age) {
+            (Some(ttl), Some(age)) => Some(ttl.min(age)),
+            (ttl, age) => ttl.or(age),
+        };
+        let ttl_left = ttl.map(|ttl| ttl.saturating_sub(live.now.since(self.written)));
+        let until_left = self
+            .until
+            .map(|until| until.duration_since(SystemTime::now()).unwrap_or_default());
+        let ttl_left = match (ttl_left, until_left) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let idle_left = live.expiry.idle.map(|idle| {
+            idle.saturating_sub(live.now.since(self.last_access.load(Ordering::Relaxed)))
+        });
+        match (ttl_left, idle_left) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Everything needed to tell whether an entry is live, read once per operation.
+#[derive(Clone, Copy)]
+struct Liveness {
+    expiry: Expiry,
+    now: ClockReading,
+}
+
+/// Reads the cache's expiry and clock for one operation. Shared by [`Cache`] and
+/// [`CacheView`] so both handles see entries expire the same way.
+fn read_liveness(
+    expires: &AtomicBool,
+    expiry: &Mutex<Expiry>,
+    clock: &EntryClock,
+    poison: PoisonPolicy,
+) -> Liveness {
+    // The common unbounded cache skips locking `expiry` on every read
+    let expiry = if expires.load(Ordering::Acquire) {
+        *expiry.lock().or_recover(poison)
+    } else {
+        Expiry::default()
+    };
+    Liveness {
+        expiry,
+        now: clock.now(),
+    }
+}
+
+/// Time since the cache was created in nanoseconds, the only time entries keep.
+struct EntryClock {
+    epoch: Instant,
+}
+
+impl EntryClock {
+    fn new() -> Self {
+        EntryClock {
+            epoch: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> ClockReading {
+        self.read(Instant::now())
+    }
+
+    /// The clock's time at `at`, negative before the cache was created.
+    fn read(&self, at: Instant) -> ClockReading {
+        let nanos = |d: Duration| i64::try_from(d.as_nanos()).unwrap_or(i64::MAX);
+        let ticks = match at.checked_duration_since(self.epoch) {
+            Some(since) => nanos(since),
+            None => -nanos(self.epoch - at),
+        };
+        ClockReading { ticks }
+    }
+}
+
+/// A time on an `EntryClock`.
+#[derive(Clone, Copy)]
+struct ClockReading {
+    ticks: i64,
+}
+
+impl ClockReading {
+    /// Time elapsed since the clock read `stamp`.
+    fn since(self, stamp: i64) -> Duration {
+        Duration::from_nanos(self.ticks.saturating_sub(stamp).max(0) as u64)
+    }
+
+    /// Whether less than `limit` has elapsed since `stamp`.
+    fn within(self, stamp: i64, limit: Duration) -> bool {
+        self.since(stamp) < limit
+    }
+}
+
+/// Unwraps a lock result according to the cache's [`PoisonPolicy`].
+trait OrRecover<G> {
+    fn or_recover(self, policy: PoisonPolicy) -> G;
+}
+
+impl<G> OrRecover<G> for LockResult<G> {
+    fn or_recover(self, policy: PoisonPolicy) -> G {
+        match policy {
+            PoisonPolicy::Abort => self.unwrap(),
+            PoisonPolicy::Recover => self.unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+}
+
+/// Takes the write lock if it is free right now, without waiting on other holders.
+fn try_write<T>(lock: &CacheLock<T>, policy: PoisonPolicy) -> Option<CacheWriteGuard<'_, T>> {
+    match lock.try_write() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::WouldBlock) => None,
+        Err(TryLockError::Poisoned(e)) => Some(Err(e).or_recover(policy)),
+    }
+}
+
+/// Takes the read lock if it is free right now, without waiting on a writer.
+fn try_read<T>(lock: &CacheLock<T>, policy: PoisonPolicy) -> Option<CacheReadGuard<'_, T>> {
+    match lock.try_read() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::WouldBlock) => None,
+        Err(TryLockError::Poisoned(e)) => Some(Err(e).or_recover(policy)),
+    }
+}
+
+/// How long to back off between attempts at taking a lock before a deadline.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Takes the read lock, giving up once `deadline` has passed. Without a deadline
+/// this waits as long as it takes, like `read()`.
+fn read_until<T>(
+    lock: &CacheLock<T>,
+    deadline: Option<Instant>,
+    policy: PoisonPolicy,
+) -> Result<CacheReadGuard<'_, T>, Timeout> {
+    let Some(deadline) = deadline else {
+        return Ok(lock.read().or_recover(policy));
+    };
+    loop {
+        if let Some(guard) = try_read(lock, policy) {
+            return Ok(guard);
+        }
+        if Instant::now() >= deadline {
+            return Err(Timeout);
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Like `read_until`, for the write lock.
+fn write_until<T>(
+    lock: &CacheLock<T>,
+    deadline: Option<Instant>,
+    policy: PoisonPolicy,
+) -> Result<CacheWriteGuard<'_, T>, Timeout> {
+    let Some(deadline) = deadline else {
+        return Ok(lock.write().or_recover(policy));
+    };
+    loop {
+        if let Some(guard) = try_write(lock, policy) {
+            return Ok(guard);
+        }
+        if Instant::now() >= deadline {
+            return Err(Timeout);
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Wakes up every thread waiting on the condition, e.g. `put_blocking` callers
+/// waiting for room in the cache.
+fn notify_room(room: &(Mutex<()>, Condvar), policy: PoisonPolicy) {
+    let (lock, cvar) = room;
+    let _guard = lock.lock().or_recover(policy);
+    cvar.notify_all();
+}
+
+/// Yeets the oldest keys until there is room for one more entry under `max`,
+/// returning the evicted entries.
+fn make_room<K: Ord, V>(
+    insert_guard: &mut BTreeMap<u64, K>,
+    data_guard: &mut BTreeMap<K, Entry<V>>,
+    max: Option<usize>,
+) -> Vec<(K, Entry<V>)> {
+    let mut evicted = Vec::new();
+    if let Some(max) = max {
+        while data_guard.len() >= max {
+            match insert_guard.pop_first() {
+                Some((_, o)) => evicted.extend(data_guard.remove_entry(&o)),
+                None => break,
+            };
+        }
+    }
+    evicted
+}
+
+/// Like `make_room`, but gives entries read since they were last considered a second
+/// chance: they go to the back of the order with a fresh sequence number from `next_seq`
+/// instead of being evicted.
+fn make_room_clock<K: Ord + Clone, V>(
+    insert_guard: &mut BTreeMap<u64, K>,
+    data_guard: &mut BTreeMap<K, Entry<V>>,
+    max: Option<usize>,
+    next_seq: &AtomicU64,
+) -> Vec<(K, Entry<V>)> {
+    let mut evicted = Vec::new();
+    if let Some(max) = max {
+        while data_guard.len() >= max {
+            let Some((_, k)) = insert_guard.pop_first() else {
+                break;
+            };
+            match data_guard.get_mut(&k) {
+                Some(e) if e.referenced.swap(false, Ordering::Relaxed) => {
+                    e.seq = next_seq.fetch_add(1, Ordering::Relaxed);
+                    insert_guard.insert(e.seq, k);
+                }
+                Some(_) => evicted.extend(data_guard.remove_entry(&k)),
+                None => {}
+            }
+        }
+    }
+    evicted
+}
+
+/// Hands every dirty entry that left the cache to the write-back callback, if one is set.
+fn flush_evicted<K, V>(
+    write_back: &RwLock<Option<FlushFn<K, V>>>,
+    evicted: &[(K, Entry<V>)],
+    policy: PoisonPolicy,
+) {
+    if let Some(flush) = write_back.read().or_recover(policy).as_ref() {
+        for (k, e) in evicted.iter().filter(|(_, e)| e.dirty) {
+            flush(k, &e.value);
+        }
+    }
+}
+
+/// Hit and miss counters for cache reads, as returned by [`Cache::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Everything the cache knows about one entry, as returned by [`Cache::debug_dump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryDebug<K, V> {
+    pub key: K,
+    pub value: V,
+    /// Time since the entry was written
+    pub age: Duration,
+    /// Time left before the entry expires, None if it never does
+    pub remaining: Option<Duration>,
+    /// Whether the entry is past its expiry but not swept yet
+    pub expired: bool,
+    /// Position of the entry in the eviction order, 0 being the next to go
+    pub position: usize,
+}
+
+/// Error returned when a blocking cache operation gives up waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out waiting on the cache")
+    }
+}
+
+impl Error for Timeout {}
+
+/// Error returned by [`Cache::try_put`] when a full cache rejects a new key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+impl fmt::Display for Full {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the cache is full")
+    }
+}
+
+impl Error for Full {}
+
+/// Why `put_entry` did not store a value.
+enum Rejected {
+    Full,
+    Timeout,
+    Disabled,
+    Invalid,
+}
+
+/// What an [`Cache::insert`] did, in more detail than the previous value `put` returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome<K, V> {
+    /// The key wasn't in the cache
+    New,
+    /// The key had a live value, which was replaced
+    Replaced(V),
+    /// The key had a value past its expiry that hadn't been swept yet, which was replaced
+    RefreshedExpired(V),
+    /// The cache was full, so an entry was evicted to make room. `replaced` is the key's
+    /// previous value, if it had one, and `victim` the oldest entry evicted
+    Evicted {
+        replaced: Option<V>,
+        victim: Option<(K, V)>,
+    },
+    /// The value wasn't stored, because the cache is full and rejects new keys,
+    /// is disabled with a zero TTL, or the value fails the validator
+    Rejected,
+}
+
+impl<K, V> InsertOutcome<K, V> {
+    /// The value the key had before, live or expired, as `put` returns it.
+    pub fn into_replaced(self) -> Option<V> {
+        match self {
+            InsertOutcome::Replaced(v) | InsertOutcome::RefreshedExpired(v) => Some(v),
+            InsertOutcome::Evicted { replaced, .. } => replaced,
+            InsertOutcome::New | InsertOutcome::Rejected => None,
+        }
+    }
+}
+
+/// When entries expire, whichever of the limits comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Expiry {
+    /// Absolute lifetime of an entry, counted from when it was put
+    pub ttl: Option<Duration>,
+    /// How long an entry may go without being read, reset by every `get` or `exists`
+    pub idle: Option<Duration>,
+    /// Hard cap on the age of an entry, which even a longer per-key time-to-live can't exceed
+    pub max_age: Option<Duration>,
+}
+
+/// Values that know when they expire, e.g. a token carrying its own expiration time.
+/// See [`Cache::with_value_expiry`].
+pub trait HasExpiry {
+    /// When the value stops being valid, or None if it doesn't expire by itself
+    fn expires_at(&self) -> Option<Instant>;
+}
+
+/// What the cache does when one of its locks was poisoned by a thread panicking while holding it.
+/// With the `parking_lot` feature the locks guarding the entries are never poisoned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoisonPolicy {
+    /// Panic on every following operation, like `unwrap`ing the lock
+    #[default]
+    Abort,
+    /// Keep operating on the data as the panicking thread left it, which may be
+    /// inconsistent (e.g. a key missing from the insert order) but stays usable
+    Recover,
+}
+
+/// A copy of the live entries of a cache, taken with [`Cache::checkpoint`]
+/// and rolled back to with [`Cache::restore`].
+#[derive(Clone)]
+pub struct Checkpoint<K, V> {
+    data: BTreeMap<K, Entry<V>>,
+    insert_order: BTreeMap<u64, K>,
+}
+
+/// Mutable access to a value in the cache, returned by [`Cache::entry_mut`].
+/// It holds the cache's write lock until it is dropped.
+pub struct EntryGuard<'a, K: Ord, V> {
+    guard: CacheWriteGuard<'a, BTreeMap<K, Entry<V>>>,
+    key: K,
+    /// The cache's validator, with the value and dirty flag to restore if the change fails it
+    validation: Option<(ValidatorFn<V>, V, bool)>,
+}
+
+impl<K: Ord, V> Deref for EntryGuard<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.guard[&self.key].value
+    }
+}
+
+impl<K: Ord, V> DerefMut for EntryGuard<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        // The key can't have been removed while the guard holds the lock
+        let entry = self.guard.get_mut(&self.key).unwrap();
+        entry.dirty = true;
+        &mut entry.value
+    }
+}
+
+impl<K: Ord, V> Drop for EntryGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let Some((valid, original, dirty)) = self.validation.take() else {
+            return;
+        };
+        let entry = self.guard.get_mut(&self.key).unwrap();
+        if !valid(&entry.value) {
+            entry.value = original;
+            entry.dirty = dirty;
+        }
+    }
+}
+
+/// A flag shared with the host application to stop a cache's cleanup thread, e.g. as
+/// part of an orchestrated shutdown. See [`Cache::with_cancel`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    /// A token that is not cancelled yet.
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Trips the token, waking up every cleanup thread using it so they exit right away.
+    pub fn cancel(&self) {
+        let (lock, cvar) = &*self.state;
+        *lock.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        cvar.notify_all();
+    }
+
+    /// Whether `cancel` was called on this token or one of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sleeps for `timeout`, or less if the token is tripped or woken up meanwhile.
+    fn sleep(&self, timeout: Duration) {
+        let (lock, cvar) = &*self.state;
+        let cancelled = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if !*cancelled {
+            drop(cvar.wait_timeout(cancelled, timeout));
+        }
+    }
+
+    /// Wakes up sleeping threads without tripping the token.
+    fn wake(&self) {
+        let (lock, cvar) = &*self.state;
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        cvar.notify_all();
+    }
+}
+
+/// What a size-bounded cache does with a new key once it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullPolicy {
+    /// Evict the oldest key to make room
+    #[default]
+    EvictOldest,
+    /// Keep the cache as it is and drop the new key. Overwrites of keys
+    /// already in the cache still go through
+    RejectNew,
+}
+
+/// Which key a full cache evicts to make room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// The oldest key, by when it was inserted
+    #[default]
+    Fifo,
+    /// The oldest key that hasn't been read since it was last considered for eviction.
+    /// A read key gets a second chance and goes to the back of the order, a cheap
+    /// approximation of least recently used
+    Clock,
+}
+
+/// Whether the cleanup thread gives way to readers or to itself when sweeping expired keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockStrategy {
+    /// Skip a sweep when readers hold the lock and retry on the next interval,
+    /// so readers are never held up by the cleanup thread
+    #[default]
+    ReaderPriority,
+    /// Wait for the write lock on every sweep. A waiting writer keeps new readers
+    /// out, so expired keys are purged on time even under a constant stream of reads.
+    /// Needs the `parking_lot` feature, whose fair lock enforces this on every platform
+    #[cfg(feature = "parking_lot")]
+    WriterPriority,
+}
+
+/// The state of a key in the cache, as returned by [`Cache::get_state`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetState<V> {
+    /// The key is in the cache and live
+    Fresh(V),
+    /// The key is still in the cache but past its expiry
+    Expired,
+    /// The key is not in the cache
+    Absent,
+}
+
+/// Reasons a [`CacheBuilder`] refuses to build a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// A max size of 0 would evict every entry as soon as it is inserted
+    ZeroMaxSize,
+    /// A cleanup interval of 0 would make the cleanup thread spin
+    ZeroCleanupInterval,
+    /// Expired entries would sit in memory for longer than their TTL between sweeps
+    CleanupIntervalExceedsTtl,
+    /// Evicting for size needs the insertion order, which isn't tracked
+    MaxSizeWithoutOrderTracking,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroMaxSize => write!(f, "max size must be greater than 0"),
+            BuildError::ZeroCleanupInterval => {
+                write!(f, "cleanup interval must be greater than 0")
+            }
+            BuildError::CleanupIntervalExceedsTtl => {
+                write!(f, "cleanup interval must not be longer than the ttl")
+            }
+            BuildError::MaxSizeWithoutOrderTracking => {
+                write!(f, "a max size requires order tracking")
+            }
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// Reasons [`Cache::scoped`] couldn't hand back the closure's result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeError {
+    /// The builder refused to build the cache
+    Build(BuildError),
+    /// The cleanup thread panicked while the cache was in use
+    CleanupPanicked,
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeError::Build(e) => write!(f, "failed to build the cache: {e}"),
+            ScopeError::CleanupPanicked => write!(f, "the cache cleanup thread panicked"),
+        }
+    }
+}
+
+impl Error for ScopeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScopeError::Build(e) => Some(e),
+            ScopeError::CleanupPanicked => None,
+        }
+    }
+}
+
+/// Builds a [`Cache`], validating the configuration up front.
+/// Created with [`Cache::builder`].
+pub struct CacheBuilder<K, V> {
+    max_size: Option<usize>,
+    ttl: Option<Duration>,
+    cleanup_interval: Option<Duration>,
+    strict: bool,
+    track_order: bool,
+    requeue_on_overwrite: bool,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Ord + Clone + Sync + Send + 'static, V: Clone + Sync + Send + 'static> CacheBuilder<K, V> {
+    /// The max number of keys, see [`Cache::with_max_size`].
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// The time-to-live of every key, see [`Cache::with_ttl`].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// How often the cleanup thread sweeps expired keys, see [`Cache::with_cleanup_interval`].
+    pub fn cleanup_interval(mut self, interval: Duration) -> Self {
+        self.cleanup_interval = Some(interval);
+        self
+    }
+
+    /// Whether `build` rejects a cleanup interval longer than the TTL, which is the default,
+    /// or clamps it to half the TTL so entries are still swept soon after they expire.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Whether the insertion order is tracked, see [`Cache::with_order_tracking`].
+    pub fn order_tracking(mut self, track: bool) -> Self {
+        self.track_order = track;
+        self
+    }
+
+    /// Whether overwrites move a key to the back of the eviction order,
+    /// see [`Cache::with_requeue_on_overwrite`].
+    pub fn requeue_on_overwrite(mut self, requeue: bool) -> Self {
+        self.requeue_on_overwrite = requeue;
+        self
+    }
+
+    /// Validates the configuration and builds the cache.
+    pub fn build(self) -> Result<Cache<K, V>, BuildError> {
+        if self.max_size == Some(0) {
+            return Err(BuildError::ZeroMaxSize);
+        }
+        if self.cleanup_interval == Some(Duration::ZERO) {
+            return Err(BuildError::ZeroCleanupInterval);
+        }
+        if self.max_size.is_some() && !self.track_order {
+            return Err(BuildError::MaxSizeWithoutOrderTracking);
+        }
+        let mut cleanup_interval = self.cleanup_interval;
+        if let (Some(interval), Some(ttl)) = (cleanup_interval, self.ttl)
+            && !ttl.is_zero()
+            && interval > ttl
+        {
+            if self.strict {
+                return Err(BuildError::CleanupIntervalExceedsTtl);
+            }
+            cleanup_interval = Some(ttl / 2);
+        }
+
+        let mut cache = Cache::new()
+            .with_order_tracking(self.track_order)
+            .with_requeue_on_overwrite(self.requeue_on_overwrite);
+        if let Some(interval) = cleanup_interval {
+            cache = cache.with_cleanup_interval(interval);
+        }
+        if let Some(size) = self.max_size {
+            cache = cache.with_max_size(size);
+        }
+        if let Some(ttl) = self.ttl {
+            cache = cache.with_ttl(ttl);
+        }
+        Ok(cache)
+    }
+}
+
 /// The Cache structure, a generic, thread-safe in memory cache with support for size constraints and time-to-live
+///
+/// Keys are kept in the order given by their `Ord` implementation. To order them differently,
+/// see [`Cache::with_comparator`], or [`Cache::with_hasher`] to compare hashes first.
+///
+/// Reads hand out clones of the values. For values that can't or shouldn't be cloned,
+/// e.g. file handles, use an [`ArcCache`], which stores them behind an `Arc`.
 pub struct Cache<K, V> {
-    data: Arc<RwLock<BTreeMap<K, (V, Instant)>>>,
+    // Lock ordering: when more than one lock is held at a time they are always taken as
+    // `room` -> `insert_order` -> `data`, or `arrivals` -> `data`. Every other lock
+    // (`max_keys`, `expiry`, `in_flight`, `cleanup_thread`, `stop`, `write_back`,
+    // `expired_senders`, `refreshing`, the cancel token) is only held long enough to read
+    // or swap its value and no other lock is taken while holding it. Callbacks are never
+    // run while `insert_order` or `data` are held.
+    data: Arc<CacheLock<BTreeMap<K, Entry<V>>>>,
     max_keys: Mutex<Option<usize>>,
-    ttl: Mutex<Option<Duration>>,
+    expiry: Arc<Mutex<Expiry>>,
+    /// Whether `expiry` has any limit set, so reads can skip locking it when it has none
+    expires: Arc<AtomicBool>,
+    cleanup_interval: Duration,
+    refresh_on_write: bool,
+    clock: Arc<EntryClock>,
+    lock_strategy: LockStrategy,
+    poison: PoisonPolicy,
+    /// Whether `insert_order` is kept up to date, see `with_order_tracking`
+    track_order: bool,
+    /// Whether overwrites move a key to the back of `insert_order`, see `with_requeue_on_overwrite`
+    requeue_on_overwrite: bool,
+    full_policy: FullPolicy,
+    eviction_policy: EvictionPolicy,
+    /// How long `get_timed` and `put_timed` wait for a lock
+    lock_timeout: Option<Duration>,
+    on_insert: Option<ObserverFn<K, V>>,
+    validator: Option<ValidatorFn<V>>,
+    /// Cache consulted when `get` misses, see `with_fallback`
+    fallback: Option<Arc<Cache<K, V>>>,
+    promote_fallback: bool,
+    on_hit: Option<ObserverFn<K, V>>,
+    /// Time-to-live by key prefix, see `with_namespace_ttl`
+    namespace_ttls: BTreeMap<K, Duration>,
+    /// Whether a key falls in a namespace, only set once there are namespaces
+    in_namespace: Option<fn(&K, &K) -> bool>,
+    /// Value equality, set when `put`s of an equal value are skipped, see `with_skip_equal_writes`
+    equal_values: Option<fn(&V, &V) -> bool>,
+    /// Time-to-live values give themselves, see `with_value_expiry`
+    value_ttl: Option<fn(&V) -> Option<Duration>>,
+    /// Age after which `get_swr` refreshes entries in the background
+    stale_after: Option<Duration>,
+    /// Keys with a `get_swr` refresh running
+    refreshing: Arc<Mutex<BTreeSet<K>>>,
     cleanup_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Starts the cleanup thread, set once the cache is configured with anything that
+    /// expires; only caches of `'static` keys and values can have one
+    spawn_cleanup: Option<fn(&Cache<K, V>)>,
     stop: Arc<RwLock<bool>>,
-    insert_order: Arc<RwLock<VecDeque<K>>>,
+    /// Sleeps the cleanup thread between sweeps, and stops it once tripped
+    cancel: CancelToken,
+    /// Keys by insertion sequence number, so the oldest key is the first one
+    /// and any key can be dropped from the order in O(log n) using its entry's `seq`.
+    /// Each live key has exactly one sequence number, so this never outgrows `data`
+    insert_order: Arc<CacheLock<BTreeMap<u64, K>>>,
+    next_seq: AtomicU64,
+    in_flight: Mutex<BTreeMap<K, Arc<OnceLock<V>>>>,
+    write_back: Arc<RwLock<Option<FlushFn<K, V>>>>,
+    /// Channels the cleanup thread sends expired entries to, see `expired_receiver`
+    expired_senders: ExpiredSenders<K, V>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Number of entries in `data`, expired or not, kept up to date under the data write lock
+    len: Arc<AtomicUsize>,
+    high_water_mark: AtomicUsize,
+    /// Signalled whenever entries leave the cache, for `put_blocking`
+    room: Arc<(Mutex<()>, Condvar)>,
+    /// Signalled whenever entries are written, for `wait_for_key`
+    arrivals: (Mutex<()>, Condvar),
 }
 
 impl<K, V> Drop for Cache<K, V> {
     /// We need to send a "stop signal" to the TTL thread
     /// in order to properly cleanup the Cache instance.
+    /// If the last handle is dropped on the TTL thread itself (e.g. from a write-back
+    /// callback) it can't join itself, so it just lets the thread exit on the signal.
     fn drop(&mut self) {
-        *self.stop.write().unwrap() = true;
-        if let Some(h) = self.cleanup_thread.lock().unwrap().take() {
-            let res = h.join();
-            match res {
-                Ok(_) => (),
-                Err(_) => panic!("Problem dropping cache"), // Honestly not sure what to do here
-            }
+        // Panicking in drop could abort the process, and the TTL thread's panic has
+        // already been reported by the panic hook. Use `Cache::scoped` to get it as an error.
+        let _ = self.stop_cleanup();
+    }
+}
+
+impl<K, V> Cache<K, V> {
+    /// Signals the cleanup thread to stop and waits for it to exit, returning
+    /// whether it panicked. Does nothing once the thread has been stopped.
+    fn stop_cleanup(&self) -> thread::Result<()> {
+        *self.stop.write().or_recover(self.poison) = true;
+        self.cancel.wake();
+        match self.cleanup_thread.lock().or_recover(self.poison).take() {
+            Some(h) if h.thread().id() != thread::current().id() => h.join(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Default for Cache<K, V> {
+    /// A new Cache with the default setting: unbound size and no time-to-live.
+    fn default() -> Self {
+        Cache::new()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> From<BTreeMap<K, V>> for Cache<K, V> {
+    /// An unbounded Cache without time-to-live holding every entry of the map,
+    /// inserted in key order.
+    fn from(map: BTreeMap<K, V>) -> Self {
+        let cache = Cache::new();
+        for (k, v) in map {
+            cache.put(k, v);
+        }
+        cache
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> From<HashMap<K, V>> for Cache<K, V> {
+    /// An unbounded Cache without time-to-live holding every entry of the map.
+    /// The insertion order of the entries is unspecified.
+    fn from(map: HashMap<K, V>) -> Self {
+        let cache = Cache::new();
+        for (k, v) in map {
+            cache.put(k, v);
+        }
+        cache
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Cache<K, V> {
+    /// A new Cache with the default setting: unbound size and no time-to-live.
+    pub fn new() -> Self {
+        Cache {
+            data: Arc::new(CacheLock::new(BTreeMap::new())),
+            max_keys: Mutex::new(None),
+            expiry: Arc::new(Mutex::new(Expiry::default())),
+            expires: Arc::new(AtomicBool::new(false)),
+            cleanup_interval: Duration::from_millis(50),
+            refresh_on_write: true,
+            requeue_on_overwrite: false,
+            clock: Arc::new(EntryClock::new()),
+            lock_strategy: LockStrategy::default(),
+            poison: PoisonPolicy::default(),
+            track_order: true,
+            full_policy: FullPolicy::default(),
+            eviction_policy: EvictionPolicy::default(),
+            lock_timeout: None,
+            on_insert: None,
+            validator: None,
+            fallback: None,
+            promote_fallback: true,
+            on_hit: None,
+            namespace_ttls: BTreeMap::new(),
+            in_namespace: None,
+            equal_values: None,
+            value_ttl: None,
+            stale_after: None,
+            refreshing: Arc::new(Mutex::new(BTreeSet::new())),
+            cleanup_thread: Mutex::new(None),
+            spawn_cleanup: None,
+            stop: Arc::new(RwLock::new(false)),
+            cancel: CancelToken::new(),
+            insert_order: Arc::new(CacheLock::new(BTreeMap::new())),
+            next_seq: AtomicU64::new(0),
+            in_flight: Mutex::new(BTreeMap::new()),
+            write_back: Arc::new(RwLock::new(None)),
+            expired_senders: Arc::new(Mutex::new(Vec::new())),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            len: Arc::new(AtomicUsize::new(0)),
+            high_water_mark: AtomicUsize::new(0),
+            room: Arc::new((Mutex::new(()), Condvar::new())),
+            arrivals: (Mutex::new(()), Condvar::new()),
+        }
+    }
+
+    /// A builder to configure a new Cache, which validates the configuration
+    /// before building it.
+    pub fn builder() -> CacheBuilder<K, V> {
+        CacheBuilder {
+            max_size: None,
+            ttl: None,
+            cleanup_interval: None,
+            strict: true,
+            track_order: true,
+            requeue_on_overwrite: false,
+            _marker: PhantomData,
         }
     }
-}
 
-impl<K: Ord + Clone + Sync + Send + 'static, V: Clone + Sync + Send + 'static> Default
-    for Cache<K, V>
-{
-    /// A new Cache with the default setting: unbound size and no time-to-live.
-    fn default() -> Self {
-        Cache::new()
+    /// Updates how often the cleanup thread purges keys past their TTL, 50ms by default.
+    /// This has to be set before `with_ttl`, which starts the cleanup thread.
+    pub fn with_cleanup_interval(mut self, interval: Duration) -> Self {
+        self.cleanup_interval = interval;
+        self
+    }
+
+    /// Controls whether overwriting a live key with `put` resets its time-to-live.
+    /// This is the default; when disabled, overwrites keep the original insertion time
+    /// so the new value expires when the old one would have.
+    pub fn with_refresh_on_write(mut self, refresh: bool) -> Self {
+        self.refresh_on_write = refresh;
+        self
+    }
+
+    /// Controls whether overwriting a key moves it to the back of the eviction order,
+    /// as if it had been removed and inserted again. By default overwrites keep the key's
+    /// original place, so it is evicted by when it was first inserted.
+    pub fn with_requeue_on_overwrite(mut self, requeue: bool) -> Self {
+        self.requeue_on_overwrite = requeue;
+        self
+    }
+
+    /// Updates the current cache with a given max_size that
+    /// will be considered when inserting new keys.
+    /// The cache will evict the "oldest" key in the cache once
+    /// it reaches its `max_size`
+    ///
+    /// # Panics
+    /// If order tracking was turned off with `with_order_tracking(false)`.
+    pub fn with_max_size(self, size: usize) -> Self {
+        assert!(self.track_order, "a max size requires order tracking");
+        *self.max_keys.lock().or_recover(self.poison) = Some(size);
+        self
+    }
+
+    /// Changes the max size of a cache that is already in use. When shrinking, the
+    /// oldest entries are evicted right away until the cache fits the new size.
+    /// `None` removes the limit.
+    ///
+    /// # Panics
+    /// If given a size while order tracking is turned off.
+    pub fn set_max_size(&self, size: Option<usize>) {
+        assert!(
+            self.track_order || size.is_none(),
+            "a max size requires order tracking"
+        );
+        *self.max_keys.lock().or_recover(self.poison) = size;
+        match size {
+            Some(size) => self.shrink_to(size),
+            // Lifting the limit frees up room for `put_blocking` callers
+            None => notify_room(&self.room, self.poison),
+        }
+    }
+
+    /// Evicts entries in the order a full cache does, oldest first by default, until at most `target` are left,
+    /// e.g. to free memory under pressure. Unlike `set_max_size` this is a one-off trim
+    /// that leaves the configured max size as it is.
+    pub fn shrink_to(&self, target: usize) {
+        let mut insert_guard = self.insert_order.write().or_recover(self.poison);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        // `make_room` leaves space for one more entry, we only need to fit the target
+        let evicted = self.evict_for(
+            &mut insert_guard,
+            &mut data_guard,
+            Some(target.saturating_add(1)),
+        );
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+    }
+
+    /// Evicts about `fraction` of the live entries in the order a full cache does,
+    /// e.g. `0.25` drops the oldest quarter. A coarse knob for memory pressure callbacks,
+    /// see `shrink_to` to trim to a given size instead. `fraction` is clamped to `0.0..=1.0`.
+    /// Expired entries are removed along the way.
+    pub fn evict_ratio(&self, fraction: f64) {
+        let expiry = self.liveness();
+        let mut insert_guard = self.insert_order.write().or_recover(self.poison);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        // Expired entries go first, so the fraction is taken of live entries only
+        let mut evicted: Vec<_> = data_guard
+            .extract_if(.., |_, e| !e.is_live(expiry))
+            .collect();
+        for (_, e) in evicted.iter() {
+            insert_guard.remove(&e.seq);
+        }
+        let doomed = (data_guard.len() as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+        let target = data_guard.len() - doomed;
+        evicted.extend(self.evict_for(
+            &mut insert_guard,
+            &mut data_guard,
+            Some(target.saturating_add(1)),
+        ));
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+    }
+
+    /// Drops every entry but the `n` most recently inserted ones, e.g. to keep a rolling
+    /// window of recent events. This goes by insertion order whatever the eviction policy,
+    /// and leaves the configured max size as it is. Dropped entries are flushed like evicted ones.
+    pub fn retain_recent(&self, n: usize) {
+        let mut insert_guard = self.insert_order.write().or_recover(self.poison);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let mut dropped = Vec::new();
+        while data_guard.len() > n {
+            match insert_guard.pop_first() {
+                Some((_, k)) => dropped.extend(data_guard.remove_entry(&k)),
+                None => break,
+            }
+        }
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        flush_evicted(&self.write_back, &dropped, self.poison);
+    }
+
+    /// Chooses whether the cleanup thread gives way to readers (the default) or makes
+    /// them wait so expired keys are always purged on time, see [`LockStrategy`].
+    /// This has to be set before `with_ttl`, which starts the cleanup thread.
+    pub fn with_lock_strategy(mut self, strategy: LockStrategy) -> Self {
+        self.lock_strategy = strategy;
+        self
+    }
+
+    /// Makes `put`ting a value equal to the live one already there a no-op, for idempotent
+    /// refreshes: the entry keeps its insertion time, so its time-to-live isn't extended,
+    /// and neither its place in the insert order nor its dirty flag change.
+    /// Writes with an explicit expiry, like `put_with_ttl` or `put_until`, still go through.
+    pub fn with_skip_equal_writes(mut self, skip: bool) -> Self
+    where
+        V: PartialEq,
+    {
+        self.equal_values = skip.then_some(V::eq);
+        self
+    }
+
+    /// The time-to-live of the longest namespace prefix matching the key, if any.
+    fn namespace_ttl(&self, key: &K) -> Option<Duration> {
+        let in_namespace = self.in_namespace?;
+        // A prefix sorts before the keys extending it, so the longest match comes first in reverse
+        self.namespace_ttls
+            .range(..=key)
+            .rev()
+            .find(|(prefix, _)| in_namespace(key, prefix))
+            .map(|(_, ttl)| *ttl)
+    }
+
+    /// Turns off tracking the insertion order, which is on by default, to save the write
+    /// to `insert_order` on every put for overwrite-heavy workloads. Without it the cache
+    /// is a plain map with expiry: it can't evict for size, `insertion_order` is derived
+    /// by sorting the entries, and `shrink_to` and `retain_recent` don't remove anything.
+    ///
+    /// # Panics
+    /// If turned off while the cache has a max size.
+    pub fn with_order_tracking(mut self, track: bool) -> Self {
+        assert!(
+            track || self.max_size().is_none(),
+            "a max size requires order tracking"
+        );
+        self.track_order = track;
+        self
+    }
+
+    /// Ties the cleanup thread to a token shared with the host application: once
+    /// the token is cancelled the thread exits right away instead of waiting out its
+    /// interval. Reads keep ignoring expired entries, they just aren't swept anymore.
+    /// This has to be set before `with_ttl`, which starts the cleanup thread.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Chooses what a full cache does with new keys: evict the oldest key (the default)
+    /// or reject the new one, see [`FullPolicy`]. Under `RejectNew`, `put` silently drops
+    /// rejected values; use `try_put` to find out.
+    /// Expired keys the cleanup thread has not swept yet still count towards the size.
+    pub fn with_full_policy(mut self, policy: FullPolicy) -> Self {
+        self.full_policy = policy;
+        self
+    }
+
+    /// Chooses which key a full cache evicts: the oldest one (the default) or the oldest
+    /// one not read lately, see [`EvictionPolicy`]. `shrink_to` and `evict_ratio` follow it too.
+    pub fn with_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction_policy = policy;
+        self
+    }
+
+    /// Bounds how long `get_timed` and `put_timed` wait for the cache's locks before
+    /// giving up with a [`Timeout`], so a misbehaving lock holder can't stall callers
+    /// indefinitely. The other methods keep waiting as long as it takes.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Chooses whether the cache panics (the default) or keeps going when one of its locks
+    /// was poisoned by a panic, see [`PoisonPolicy`].
+    /// This has to be set before `with_ttl` so the cleanup thread follows it too.
+    pub fn with_poison_policy(mut self, policy: PoisonPolicy) -> Self {
+        self.poison = policy;
+        self
+    }
+
+    /// Turns the cache into a write-back cache: every entry written with `put` is
+    /// marked dirty, and dirty entries are handed to `flush` when they are evicted
+    /// for size or expire, so they can be persisted to a backing store.
+    /// Entries removed explicitly with `remove` are not flushed.
+    pub fn with_write_back(self, flush: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.write_back
+            .write()
+            .or_recover(self.poison)
+            .replace(Arc::new(flush));
+        self
+    }
+
+    /// Refuses to store values for which `valid` returns false, e.g. empty strings used as
+    /// a "nothing here" sentinel. Every write goes through it: `put` and the like drop
+    /// invalid values, `insert` returns `InsertOutcome::Rejected` for them, and
+    /// `get_or_insert_with` hands a computed invalid value back without caching it.
+    /// `valid` runs before the cache's locks are taken, except for counters written with
+    /// `incr` and values changed through `entry_mut`, which only exist under the write lock:
+    /// there `valid` must not use the cache. An invalid count isn't stored, and an invalid
+    /// change through `entry_mut` is undone.
+    pub fn with_value_validator(
+        mut self,
+        valid: impl Fn(&V) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Arc::new(valid));
+        self
+    }
+
+    fn is_valid(&self, val: &V) -> bool {
+        self.validator.as_ref().is_none_or(|valid| valid(val))
+    }
+
+    /// Backs the cache with `other`, e.g. a local cache in front of one shared between
+    /// workers: when `get` misses, it looks the key up in `other`, and in `other`'s own
+    /// fallback if it has one, without writing to it. A value found there is promoted into
+    /// this cache, so the next read hits locally, unless turned off with `with_fallback_promotion`.
+    /// Hits in the fallback still count as misses of this cache in its stats.
+    pub fn with_fallback(mut self, other: Arc<Cache<K, V>>) -> Self {
+        self.fallback = Some(other);
+        self
+    }
+
+    /// Whether values found in the fallback are copied into this cache, which is the default.
+    pub fn with_fallback_promotion(mut self, promote: bool) -> Self {
+        self.promote_fallback = promote;
+        self
+    }
+
+    /// Calls `f` with every key newly inserted and its value, e.g. for tracing or metrics,
+    /// whichever method wrote it. Overwrites of a live key don't count. `f` runs after
+    /// the cache's locks are released, so it may use the cache.
+    pub fn with_on_insert(mut self, f: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.on_insert = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` with the key and value every time a `get` hits, see `with_on_insert`.
+    pub fn with_on_hit(mut self, f: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.on_hit = Some(Arc::new(f));
+        self
+    }
+
+    /// Hands every dirty entry still in the cache to the write-back callback and marks it
+    /// clean, without evicting anything, e.g. to persist everything before shutting down.
+    /// Expired entries not swept yet are flushed too. Does nothing without a callback.
+    pub fn flush_dirty(&self) {
+        let Some(flush) = self.write_back.read().or_recover(self.poison).clone() else {
+            return;
+        };
+        let dirty: Vec<(K, V)> = self
+            .data
+            .write()
+            .or_recover(self.poison)
+            .iter_mut()
+            .filter(|(_, e)| e.dirty)
+            .map(|(k, e)| {
+                e.dirty = false;
+                (k.clone(), e.value.clone())
+            })
+            .collect();
+        for (k, v) in &dirty {
+            flush(k, v);
+        }
+    }
+
+    /// Wraps the configured cache in an `Arc` so it can be shared across threads.
+    ///
+    /// ```
+    /// use std::{thread, time::Duration};
+    ///
+    /// use cream::Cache;
+    ///
+    /// let cache = Cache::new().with_ttl(Duration::from_secs(5)).into_arc();
+    /// let handles: Vec<_> = (0..4)
+    ///     .map(|i| {
+    ///         let cache = cache.clone();
+    ///         thread::spawn(move || cache.put(i, i * 10))
+    ///     })
+    ///     .collect();
+    /// for h in handles {
+    ///     h.join().unwrap();
+    /// }
+    /// assert_eq!(cache.len(), 4);
+    /// ```
+    pub fn into_arc(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Creates a new, empty cache with the same configuration as this one: max size,
+    /// expiry, cleanup interval, policies and write-back callback. It gets its own
+    /// cleanup thread if this cache has one.
+    pub fn empty_like(&self) -> Self {
+        let mut cache = Cache::new();
+        cache.cleanup_interval = self.cleanup_interval;
+        cache.refresh_on_write = self.refresh_on_write;
+        cache.requeue_on_overwrite = self.requeue_on_overwrite;
+        cache.lock_strategy = self.lock_strategy;
+        cache.poison = self.poison;
+        cache.track_order = self.track_order;
+        cache.full_policy = self.full_policy;
+        cache.eviction_policy = self.eviction_policy;
+        cache.lock_timeout = self.lock_timeout;
+        cache.on_insert = self.on_insert.clone();
+        cache.validator = self.validator.clone();
+        cache.fallback = self.fallback.clone();
+        cache.promote_fallback = self.promote_fallback;
+        cache.on_hit = self.on_hit.clone();
+        cache.cancel = self.cancel.clone();
+        cache.namespace_ttls = self.namespace_ttls.clone();
+        cache.in_namespace = self.in_namespace;
+        cache.value_ttl = self.value_ttl;
+        cache.equal_values = self.equal_values;
+        cache.stale_after = self.stale_after;
+        *cache.max_keys.lock().or_recover(self.poison) = self.max_size();
+        let expiry = *self.expiry.lock().or_recover(self.poison);
+        cache.update_expiry(|current| *current = expiry);
+        *cache.write_back.write().or_recover(self.poison) =
+            self.write_back.read().or_recover(self.poison).clone();
+        cache.spawn_cleanup = self.spawn_cleanup;
+        if let Some(spawn_cleanup) = self.spawn_cleanup
+            && self.cleanup_thread.lock().or_recover(self.poison).is_some()
+        {
+            spawn_cleanup(&cache);
+        }
+        cache
+    }
+
+    /// The max number of keys in the cache, if it has a limit.
+    pub fn max_size(&self) -> Option<usize> {
+        *self.max_keys.lock().or_recover(self.poison)
+    }
+
+    /// The cache-wide time-to-live, if it has one.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expiry.lock().or_recover(self.poison).ttl
+    }
+
+    /// Puts a value into the cache for a given key.
+    /// Overwriting a key resets its time-to-live, unless disabled with `with_refresh_on_write`.
+    pub fn put(&self, key: K, val: V) -> Option<V> {
+        self.insert(key, val).into_replaced()
+    }
+
+    /// Like `put`, but tells apart whether the key was new, replaced a live or an
+    /// expired value, or whether an entry had to be evicted to make room for it.
+    pub fn insert(&self, key: K, val: V) -> InsertOutcome<K, V> {
+        self.put_entry(key, val, None, None, None)
+            .unwrap_or(InsertOutcome::Rejected)
+    }
+
+    /// Like `put`, but gives up with a `Timeout` if the locks can't be taken within
+    /// the timeout set with `with_lock_timeout`. Without one it waits like `put`.
+    pub fn put_timed(&self, key: K, val: V) -> Result<Option<V>, Timeout> {
+        let deadline = self.lock_timeout.map(|timeout| Instant::now() + timeout);
+        match self.put_entry(key, val, None, None, deadline) {
+            Ok(outcome) => Ok(outcome.into_replaced()),
+            Err(Rejected::Full | Rejected::Disabled | Rejected::Invalid) => Ok(None),
+            Err(Rejected::Timeout) => Err(Timeout),
+        }
+    }
+
+    /// Like `put`, but returns an error instead of dropping the value when the cache
+    /// is full and set to reject new keys with `FullPolicy::RejectNew`.
+    pub fn try_put(&self, key: K, val: V) -> Result<Option<V>, Full> {
+        match self.put_entry(key, val, None, None, None) {
+            Ok(outcome) => Ok(outcome.into_replaced()),
+            Err(Rejected::Disabled | Rejected::Invalid) => Ok(None),
+            // Without a deadline the only other way to be rejected is a full cache
+            Err(_) => Err(Full),
+        }
+    }
+
+    fn put_entry(
+        &self,
+        key: K,
+        val: V,
+        ttl: Option<Duration>,
+        until: Option<SystemTime>,
+        deadline: Option<Instant>,
+    ) -> Result<InsertOutcome<K, V>, Rejected> {
+        if self.is_disabled() {
+            return Err(Rejected::Disabled);
+        }
+        if !self.is_valid(&val) {
+            return Err(Rejected::Invalid);
+        }
+        let max = *self.max_keys.lock().or_recover(self.poison);
+        // Both locks are held for the whole insert so concurrent puts
+        // can never push the cache past its max size
+        let mut insert_guard = match self.track_order {
+            true => Some(write_until(&self.insert_order, deadline, self.poison)),
+            false => None,
+        }
+        .transpose()
+        .map_err(|_| Rejected::Timeout)?;
+        // Without order tracking nothing is written to the order, so an empty one will do
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard =
+            write_until(&self.data, deadline, self.poison).map_err(|_| Rejected::Timeout)?;
+        let expiry = self.liveness();
+        if let (Some(eq), None, None) = (self.equal_values, ttl, until)
+            && data_guard
+                .get(&key)
+                .is_some_and(|e| e.is_live(expiry) && eq(&e.value, &val))
+        {
+            return Ok(InsertOutcome::Replaced(val));
+        }
+        let evicted = self
+            .admit(insert_order, &mut data_guard, &key, max)
+            .ok_or(Rejected::Full)?;
+        let was_live = data_guard.get(&key).map(|e| e.is_live(expiry));
+        let until = until.map(|until| (key.clone(), until));
+        let mut observed = Vec::new();
+        let replaced =
+            self.insert_entry(insert_order, &mut data_guard, key, val, ttl, &mut observed);
+        if let Some((key, until)) = until
+            && let Some(e) = data_guard.get_mut(&key)
+        {
+            e.until = Some(until);
+        }
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.arrivals, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+        self.notify_inserted(observed);
+        Ok(match (evicted.into_iter().next(), replaced, was_live) {
+            (Some((k, e)), replaced, _) => InsertOutcome::Evicted {
+                replaced,
+                victim: Some((k, e.value)),
+            },
+            (None, None, _) => InsertOutcome::New,
+            (None, Some(v), Some(true)) => InsertOutcome::Replaced(v),
+            (None, Some(v), _) => InsertOutcome::RefreshedExpired(v),
+        })
+    }
+
+    /// Evicts until there is room for one more entry below `max`, following the eviction policy.
+    fn evict_for(
+        &self,
+        insert_guard: &mut BTreeMap<u64, K>,
+        data_guard: &mut BTreeMap<K, Entry<V>>,
+        max: Option<usize>,
+    ) -> Vec<(K, Entry<V>)> {
+        match self.eviction_policy {
+            EvictionPolicy::Fifo => make_room(insert_guard, data_guard, max),
+            EvictionPolicy::Clock => make_room_clock(insert_guard, data_guard, max, &self.next_seq),
+        }
+    }
+
+    /// Makes room for `key` according to the full policy and returns the entries evicted
+    /// for it, or None if the cache is full and rejects new keys.
+    fn admit(
+        &self,
+        insert_guard: &mut BTreeMap<u64, K>,
+        data_guard: &mut BTreeMap<K, Entry<V>>,
+        key: &K,
+        max: Option<usize>,
+    ) -> Option<Vec<(K, Entry<V>)>> {
+        match self.full_policy {
+            // Overwrites never grow the cache, so they don't need room
+            _ if data_guard.contains_key(key) => Some(Vec::new()),
+            FullPolicy::EvictOldest => Some(self.evict_for(insert_guard, data_guard, max)),
+            FullPolicy::RejectNew => max.is_none_or(|max| data_guard.len() < max).then(Vec::new),
+        }
+    }
+
+    /// Puts all entries into the cache under a single write lock, so readers see
+    /// either none or all of them. Evictions needed to make room happen in the same
+    /// critical section. Under `FullPolicy::RejectNew`, the new keys that don't fit are dropped.
+    pub fn put_transaction(&self, mut entries: Vec<(K, V)>) {
+        if self.is_disabled() {
+            return;
+        }
+        entries.retain(|(_, val)| self.is_valid(val));
+        let max = *self.max_keys.lock().or_recover(self.poison);
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let mut evicted = Vec::new();
+        let mut observed = Vec::new();
+        for (key, val) in entries {
+            if let Some(room) = self.admit(insert_order, &mut data_guard, &key, max) {
+                evicted.extend(room);
+                self.insert_entry(insert_order, &mut data_guard, key, val, None, &mut observed);
+            }
+        }
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.arrivals, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+        self.notify_inserted(observed);
+    }
+
+    /// Puts a value only if `version` is newer than the one stored for the key, for
+    /// last-write-wins between replicas. Returns whether the value was stored.
+    /// A missing or expired key always takes the value, and plain `put`s count as version 0.
+    pub fn put_versioned(&self, key: K, val: V, version: u64) -> bool {
+        if self.is_disabled() || !self.is_valid(&val) {
+            return false;
+        }
+        let expiry = self.liveness();
+        let max = *self.max_keys.lock().or_recover(self.poison);
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        if data_guard
+            .get(&key)
+            .is_some_and(|e| e.is_live(expiry) && e.version >= version)
+        {
+            return false;
+        }
+        let Some(evicted) = self.admit(insert_order, &mut data_guard, &key, max) else {
+            return false;
+        };
+        let mut observed = Vec::new();
+        self.insert_entry(
+            insert_order,
+            &mut data_guard,
+            key.clone(),
+            val,
+            None,
+            &mut observed,
+        );
+        if let Some(e) = data_guard.get_mut(&key) {
+            e.version = version;
+        }
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.arrivals, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+        self.notify_inserted(observed);
+        true
+    }
+
+    /// Preloads entries with the instants they were originally inserted at, e.g. when
+    /// restoring from a snapshot that recorded them, so they keep their age instead
+    /// of starting a fresh time-to-live like with `put`. Entries that are already too old
+    /// are never returned. The entries are inserted in order, so the last ones are the
+    /// newest for eviction, and are not flushed to the write-back callback unless rewritten.
+    pub fn warm_up(&self, entries: impl IntoIterator<Item = (K, V, Instant)>) {
+        if self.is_disabled() {
+            return;
+        }
+        // The validator is user code, which mustn't run under the locks
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|(_, val, _)| self.is_valid(val))
+            .collect();
+        let max = *self.max_keys.lock().or_recover(self.poison);
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let mut evicted = Vec::new();
+        let mut observed = Vec::new();
+        for (key, val, inserted) in entries {
+            let Some(room) = self.admit(insert_order, &mut data_guard, &key, max) else {
+                continue;
+            };
+            evicted.extend(room);
+            self.insert_entry(
+                insert_order,
+                &mut data_guard,
+                key.clone(),
+                val,
+                None,
+                &mut observed,
+            );
+            if let Some(e) = data_guard.get_mut(&key) {
+                e.written = self.clock.read(inserted).ticks;
+                e.dirty = false;
+            }
+        }
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.arrivals, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+        self.notify_inserted(observed);
+    }
+
+    /// Puts every live entry of `other` into this cache, e.g. to consolidate per-thread
+    /// caches. When a key is live in both, `conflict` gets this cache's value and
+    /// the other one's, and its result is stored.
+    /// The entries are written with `put_transaction`, after `conflict` ran, so a value
+    /// written to this cache concurrently may be overwritten with one resolved against
+    /// its older value.
+    pub fn merge(&self, other: &Cache<K, V>, conflict: impl Fn(&V, &V) -> V) {
+        let theirs: Vec<(K, V)> = other.iter_live();
+        let expiry = self.liveness();
+        let data_guard = self.data.read().or_recover(self.poison);
+        let mine: Vec<Option<V>> = theirs
+            .iter()
+            .map(|(k, _)| {
+                data_guard
+                    .get(k)
+                    .filter(|e| e.is_live(expiry))
+                    .map(|e| e.value.clone())
+            })
+            .collect();
+        drop(data_guard);
+        let merged = theirs
+            .into_iter()
+            .zip(mine)
+            .map(|((k, theirs), mine)| match mine {
+                Some(mine) => (k, conflict(&mine, &theirs)),
+                None => (k, theirs),
+            })
+            .collect();
+        self.put_transaction(merged);
+    }
+
+    /// Clones every live entry, in key order.
+    fn iter_live(&self) -> Vec<(K, V)> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+
+    /// Write-locks the insert order, unless the cache doesn't track it. Callers stand in an
+    /// empty order for the missing one, since nothing is written to it then.
+    fn order_write(&self) -> Option<CacheWriteGuard<'_, BTreeMap<u64, K>>> {
+        self.track_order
+            .then(|| self.insert_order.write().or_recover(self.poison))
+    }
+
+    fn liveness(&self) -> Liveness {
+        read_liveness(&self.expires, &self.expiry, &self.clock, self.poison)
+    }
+
+    /// Changes the cache-wide expiry, keeping `expires` in step with it.
+    fn update_expiry(&self, update: impl FnOnce(&mut Expiry)) {
+        let mut expiry = self.expiry.lock().or_recover(self.poison);
+        update(&mut expiry);
+        self.expires
+            .store(*expiry != Expiry::default(), Ordering::Release);
+    }
+
+    /// Whether the cache was configured with a zero TTL, meaning it never stores anything.
+    fn is_disabled(&self) -> bool {
+        self.expires.load(Ordering::Acquire)
+            && self.expiry.lock().or_recover(self.poison).ttl == Some(Duration::ZERO)
+    }
+
+    /// Inserts or overwrites an entry with both locks already held.
+    /// An overwritten key keeps its original place in the insert order. A key that wasn't
+    /// live is added to `observed` for `notify_inserted` once the locks are released.
+    fn insert_entry(
+        &self,
+        insert_guard: &mut BTreeMap<u64, K>,
+        data_guard: &mut BTreeMap<K, Entry<V>>,
+        key: K,
+        val: V,
+        entry_ttl: Option<Duration>,
+        observed: &mut Vec<(K, V)>,
+    ) -> Option<V> {
+        let expiry = self.liveness();
+        let entry_ttl = entry_ttl
+            .or_else(|| self.value_ttl.and_then(|value_ttl| value_ttl(&val)))
+            .or_else(|| self.namespace_ttl(&key));
+        if let (Some(_), Some(spawn_cleanup)) = (entry_ttl, self.spawn_cleanup) {
+            spawn_cleanup(self);
+        }
+        let existing = data_guard.get(&key);
+        let was_live = existing.is_some_and(|e| e.is_live(expiry));
+        if !was_live && self.on_insert.is_some() {
+            observed.push((key.clone(), val.clone()));
+        }
+        let kept_written = existing
+            .filter(|_| was_live && !self.refresh_on_write)
+            .map(|e| e.written);
+        let seq = match existing {
+            Some(e) if !(self.requeue_on_overwrite && self.track_order) => e.seq,
+            _ => {
+                // A key keeps one place in the order, so overwrites never grow it
+                if let Some(e) = existing {
+                    insert_guard.remove(&e.seq);
+                }
+                let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+                if self.track_order {
+                    insert_guard.insert(seq, key.clone());
+                }
+                seq
+            }
+        };
+        // The write counts as activity for idle expiry even when it keeps the written time
+        let mut entry = Entry::new(val, seq, self.clock.now().ticks);
+        entry.ttl = entry_ttl;
+        if let Some(written) = kept_written {
+            entry.written = written;
+        }
+        let inserted = data_guard.insert(key, entry).map(|e| e.value);
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        self.high_water_mark
+            .fetch_max(data_guard.len(), Ordering::Relaxed);
+        inserted
+    }
+
+    /// Calls the `on_insert` observer with the keys `insert_entry` newly inserted.
+    fn notify_inserted(&self, observed: Vec<(K, V)>) {
+        if let Some(on_insert) = &self.on_insert {
+            for (key, val) in &observed {
+                on_insert(key, val);
+            }
+        }
+    }
+
+    /// Puts a value into the cache like `put`, but instead of evicting when the cache
+    /// is full it blocks until another thread makes room (by removing an entry or
+    /// by entries expiring) or until `timeout` elapses.
+    /// Overwriting a key that is already in the cache never blocks.
+    pub fn put_blocking(&self, key: K, val: V, timeout: Duration) -> Result<Option<V>, Timeout> {
+        if self.is_disabled() || !self.is_valid(&val) {
+            return Ok(None);
+        }
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.room;
+        let mut room_guard = lock.lock().or_recover(self.poison);
+        loop {
+            let max = *self.max_keys.lock().or_recover(self.poison);
+            let mut insert_guard = self.order_write();
+            let mut untracked = BTreeMap::new();
+            let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+            let mut data_guard = self.data.write().or_recover(self.poison);
+            if max.is_none_or(|max| data_guard.len() < max || data_guard.contains_key(&key)) {
+                let mut observed = Vec::new();
+                let inserted =
+                    self.insert_entry(insert_order, &mut data_guard, key, val, None, &mut observed);
+                drop(data_guard);
+                drop(insert_guard);
+                notify_room(&self.arrivals, self.poison);
+                self.notify_inserted(observed);
+                return Ok(inserted);
+            }
+            drop(data_guard);
+            drop(insert_guard);
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Timeout);
+            }
+            room_guard = cvar
+                .wait_timeout(room_guard, remaining)
+                .or_recover(self.poison)
+                .0;
+        }
+    }
+
+    /// Blocks until the given key is in the cache, e.g. once another thread produced it,
+    /// and returns its value, or `None` if it did not show up within `timeout`.
+    /// Doesn't count towards the hit and miss stats.
+    pub fn wait_for_key(&self, key: &K, timeout: Duration) -> Option<V> {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &self.arrivals;
+        let mut arrivals_guard = lock.lock().or_recover(self.poison);
+        loop {
+            if let Some(v) = self.get_live(key) {
+                return Some(v);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            arrivals_guard = cvar
+                .wait_timeout(arrivals_guard, remaining)
+                .or_recover(self.poison)
+                .0;
+        }
+    }
+
+    /// Takes a copy of every live entry along with the insert order, so the cache can
+    /// be rolled back to this point with `restore`, e.g. after speculative writes.
+    pub fn checkpoint(&self) -> Checkpoint<K, V> {
+        let expiry = self.liveness();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        let data: BTreeMap<K, Entry<V>> = data_guard
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .collect();
+        let insert_order = insert_guard
+            .iter()
+            .filter(|(_, k)| data.contains_key(k))
+            .map(|(seq, k)| (*seq, k.clone()))
+            .collect();
+        Checkpoint { data, insert_order }
+    }
+
+    /// Rolls the cache back to a checkpoint, discarding every change made since.
+    /// Entries keep the age they had, so ones that expired in the meantime stay expired.
+    /// Entries dropped by the rollback are not flushed to the write-back callback.
+    pub fn restore(&self, checkpoint: Checkpoint<K, V>) {
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        *insert_order = checkpoint.insert_order;
+        *data_guard = checkpoint.data;
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        notify_room(&self.arrivals, self.poison);
+    }
+
+    /// Gets the current value in the cache for the given key. Returns None if
+    /// the key does not exist or is past its time-to-live, if it has one.
+    /// The key may be given as any borrowed form of `K`, like with `BTreeMap::get`,
+    /// e.g. a `&str` for a `Cache<String, V>`.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let val = match &self.on_hit {
+            None => self.get_live(key),
+            // The hook wants the owned key, which only the map has
+            Some(on_hit) => self.get_live_key_value(key).map(|(k, v)| {
+                on_hit(&k, &v);
+                v
+            }),
+        };
+        self.record_read(val.is_some());
+        val.or_else(|| self.get_fallback(key).map(|(_, v)| v))
+    }
+
+    /// Looks the key up in the fallback chain after a miss, promoting what is found
+    /// unless promotion is turned off.
+    fn get_fallback<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let fallback = self.fallback.as_ref()?;
+        let (k, v) = fallback
+            .get_live_key_value(key)
+            .or_else(|| fallback.get_fallback(key))?;
+        if self.promote_fallback {
+            self.put(k.clone(), v.clone());
+        }
+        Some((k, v))
+    }
+
+    /// Like `get`, but gives up with a `Timeout` if the read lock can't be taken within
+    /// the timeout set with `with_lock_timeout`. Without one it waits like `get`.
+    pub fn get_timed(&self, key: &K) -> Result<Option<V>, Timeout> {
+        let deadline = self.lock_timeout.map(|timeout| Instant::now() + timeout);
+        let expiry = self.liveness();
+        let val = read_until(&self.data, deadline, self.poison)?
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .map(|e| {
+                e.touch(&self.clock);
+                e.value.clone()
+            });
+        self.record_read(val.is_some());
+        Ok(val)
+    }
+
+    /// Like `get`, but clones the value into `out` with `clone_from`, which lets
+    /// values such as `Vec` reuse the allocation `out` already has.
+    /// Returns false, leaving `out` untouched, if the key does not exist or is expired.
+    pub fn get_into(&self, key: &K, out: &mut V) -> bool {
+        let expiry = self.liveness();
+        let found = match self.data.read().or_recover(self.poison).get(key) {
+            Some(e) if e.is_live(expiry) => {
+                e.touch(&self.clock);
+                out.clone_from(&e.value);
+                true
+            }
+            _ => false,
+        };
+        self.record_read(found);
+        found
+    }
+
+    /// Like `get`, but tells apart a key that is not in the cache from one that
+    /// is still there past its expiry and has not been cleaned up yet.
+    pub fn get_state(&self, key: &K) -> GetState<V> {
+        let expiry = self.liveness();
+        let state = match self.data.read().or_recover(self.poison).get(key) {
+            Some(e) if e.is_live(expiry) => {
+                e.touch(&self.clock);
+                GetState::Fresh(e.value.clone())
+            }
+            Some(_) => GetState::Expired,
+            None => GetState::Absent,
+        };
+        self.record_read(matches!(state, GetState::Fresh(_)));
+        state
+    }
+
+    /// Gives mutable access to a live value in place, without cloning it, or None if
+    /// the key does not exist or is expired. The change doesn't reset the entry's time-to-live.
+    /// The guard holds the write lock until it is dropped, so every other access to the cache
+    /// waits for it meanwhile: keep it short-lived, and never use the cache from the same
+    /// thread while holding it, which deadlocks. With a validator set, the value is cloned
+    /// up front and put back when the guard drops if the changed value isn't valid.
+    pub fn entry_mut(&self, key: &K) -> Option<EntryGuard<'_, K, V>> {
+        let expiry = self.liveness();
+        let guard = self.data.write().or_recover(self.poison);
+        let e = guard.get(key).filter(|e| e.is_live(expiry))?;
+        e.touch(&self.clock);
+        let validation = self
+            .validator
+            .clone()
+            .map(|valid| (valid, e.value.clone(), e.dirty));
+        Some(EntryGuard {
+            guard,
+            key: key.clone(),
+            validation,
+        })
+    }
+
+    /// Like `get`, but also returns a value past its expiry, e.g. to serve stale data
+    /// when loading a fresh value failed. Expired values are only available until the
+    /// cleanup thread sweeps them, so whether one is found depends on the cleanup timing.
+    pub fn get_allow_stale(&self, key: &K) -> Option<V> {
+        let expiry = self.liveness();
+        let val = self.data.read().or_recover(self.poison).get(key).map(|e| {
+            // Reading an expired entry must not make it look recently used again
+            if e.is_live(expiry) {
+                e.touch(&self.clock);
+            }
+            e.value.clone()
+        });
+        self.record_read(val.is_some());
+        val
+    }
+
+    /// Gets the values of two keys under a single read lock, so both come from the
+    /// same snapshot of the cache. Each is None if its key does not exist or is expired.
+    pub fn get_pair(&self, a: &K, b: &K) -> (Option<V>, Option<V>) {
+        let expiry = self.liveness();
+        let data_guard = self.data.read().or_recover(self.poison);
+        let lookup = |key: &K| {
+            data_guard.get(key).filter(|e| e.is_live(expiry)).map(|e| {
+                e.touch(&self.clock);
+                e.value.clone()
+            })
+        };
+        let pair = (lookup(a), lookup(b));
+        drop(data_guard);
+        self.record_read(pair.0.is_some());
+        self.record_read(pair.1.is_some());
+        pair
+    }
+
+    /// Gets the values of all the given keys under a single read lock, keyed by key.
+    /// Keys that don't exist or are expired are left out of the map.
+    pub fn get_map(&self, keys: &[K]) -> BTreeMap<K, V> {
+        let expiry = self.liveness();
+        let data_guard = self.data.read().or_recover(self.poison);
+        // Every key given counts as a read, repeated ones included
+        let mut hits = 0;
+        let found: BTreeMap<K, V> = keys
+            .iter()
+            .filter_map(|key| {
+                let e = data_guard.get(key).filter(|e| e.is_live(expiry))?;
+                e.touch(&self.clock);
+                hits += 1;
+                Some((key.clone(), e.value.clone()))
+            })
+            .collect();
+        drop(data_guard);
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses
+            .fetch_add(keys.len() as u64 - hits, Ordering::Relaxed);
+        found
+    }
+
+    /// Like `get_map`, but the keys that are missing or expired are all computed in one
+    /// call to `compute`, which only gets those keys. The computed values are stored
+    /// and returned along with the cached ones.
+    pub fn get_or_compute_batch<F>(&self, keys: &[K], compute: F) -> BTreeMap<K, V>
+    where
+        F: FnOnce(&[K]) -> BTreeMap<K, V>,
+    {
+        let mut found = self.get_map(keys);
+        let missing: Vec<K> = keys
+            .iter()
+            .filter(|k| !found.contains_key(k))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if missing.is_empty() {
+            return found;
+        }
+        let computed = compute(&missing);
+        self.put_transaction(
+            computed
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        found.extend(computed);
+        found
+    }
+
+    /// Counts a read towards the hit/miss stats.
+    fn record_read(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Like `get_live`, but also clones the key as stored in the cache.
+    fn get_live_key_value<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get_key_value(key)
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| {
+                e.touch(&self.clock);
+                (k.clone(), e.value.clone())
+            })
+    }
+
+    /// Looks up a live value, counting the access on the entry but not towards the stats.
+    fn get_live<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .map(|e| {
+                e.touch(&self.clock);
+                e.value.clone()
+            })
+    }
+
+    /// Gets the current value for the given key, or computes it with `f` and
+    /// inserts it if the key is missing or expired.
+    /// Concurrent callers missing on the same key are deduplicated: only the first
+    /// one runs `f`, the others wait for it and receive the same value.
+    /// The waiters are all woken by a single notification once the value is ready
+    /// and take it straight from the loader, without going back through the cache's locks.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+        self.get_or_load(key, f, None)
+    }
+
+    /// Like `get_or_insert_with`, for loaders that return `None` when there is nothing
+    /// to load. Only `Some` results are cached: a `None` is handed back without being
+    /// inserted, so the next call runs `f` again instead of remembering a transient miss.
+    /// Unlike `get_or_insert_with`, concurrent misses on the same key each run `f`.
+    pub fn get_or_insert_opt(&self, key: K, f: impl FnOnce() -> Option<V>) -> Option<V> {
+        if let Some(v) = self.get(&key) {
+            return Some(v);
+        }
+        let v = f()?;
+        self.put(key, v.clone());
+        Some(v)
+    }
+
+    /// Wraps a pure function so its results are cached by argument: calling the
+    /// returned closure goes through `get_or_insert_with`, so `f` only runs for
+    /// arguments that are missing or expired.
+    pub fn memoize<'a, F>(&'a self, f: F) -> impl Fn(K) -> V + 'a
+    where
+        F: Fn(K) -> V + 'a,
+    {
+        move |arg: K| self.get_or_insert_with(arg.clone(), || f(arg))
+    }
+
+    /// Single-flight read-through shared by the `get_or_insert_with` variants.
+    fn get_or_load(&self, key: K, f: impl FnOnce() -> V, ttl: Option<Duration>) -> V {
+        if let Some(v) = self.get(&key) {
+            return v;
+        }
+        let cell = self
+            .in_flight
+            .lock()
+            .or_recover(self.poison)
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut leader = false;
+        // Waiters block on the cell rather than polling the cache, and all get the
+        // leader's value when it's set.
+        let val = cell
+            .get_or_init(|| {
+                leader = true;
+                // Another loader may have finished between our miss and getting the cell
+                self.get_live(&key).unwrap_or_else(|| {
+                    let v = f();
+                    // `get_or_insert_with_ttl` has started the cleanup thread for the TTL
+                    let _ = self.put_entry(key.clone(), v.clone(), ttl, None, None);
+                    v
+                })
+            })
+            .clone();
+        if leader {
+            let mut in_flight = self.in_flight.lock().or_recover(self.poison);
+            if in_flight.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+                in_flight.remove(&key);
+            }
+        }
+        val
+    }
+
+    /// Return an iterator over all keys in the cache.
+    /// This will exclude any keys that are past the time-to-live.
+    /// The iterator knows its exact length, so collecting it allocates once.
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = K> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Returns the keys tracked for eviction in the order they were first inserted, oldest first.
+    /// This includes expired keys the cleanup thread has not purged yet.
+    pub fn insertion_order(&self) -> Vec<K> {
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        self.in_insertion_order(&insert_guard, &data_guard)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// The `n` most recently inserted live entries, newest first, e.g. to page through recent events.
+    pub fn recent(&self, n: usize) -> Vec<(K, V)> {
+        let expiry = self.liveness();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        self.in_insertion_order(&insert_guard, &data_guard)
+            .rev()
+            .filter(|(_, e)| e.is_live(expiry))
+            .take(n)
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+
+    /// The `n` live entries that were inserted first, oldest first.
+    pub fn oldest(&self, n: usize) -> Vec<(K, V)> {
+        let expiry = self.liveness();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        self.in_insertion_order(&insert_guard, &data_guard)
+            .filter(|(_, e)| e.is_live(expiry))
+            .take(n)
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+            .collect()
+    }
+
+    /// Up to `n` live entries picked at random, e.g. to spot-check values in a large cache
+    /// without copying all of it. Picked in one pass under the read lock with reservoir
+    /// sampling, so every live entry is equally likely to be in the sample.
+    pub fn sample(&self, n: usize) -> Vec<(K, V)> {
+        // Seeded from the hasher's per-process random keys, with no RNG dependency
+        let mut state = RandomState::new().hash_one(Instant::now()) | 1;
+        let mut next_random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let expiry = self.liveness();
+        let mut sample = Vec::with_capacity(n);
+        let data_guard = self.data.read().or_recover(self.poison);
+        for (seen, (k, e)) in data_guard
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .enumerate()
+        {
+            if seen < n {
+                sample.push((k.clone(), e.value.clone()));
+            } else {
+                let slot = (next_random() % (seen as u64 + 1)) as usize;
+                if slot < n {
+                    sample[slot] = (k.clone(), e.value.clone());
+                }
+            }
+        }
+        sample
+    }
+
+    /// Every live entry in the order the keys were inserted, oldest first, where `keys`
+    /// and `values` go by key order. Expired entries not swept yet are skipped.
+    pub fn iter_ordered(&self) -> Vec<(K, V)> {
+        self.oldest(usize::MAX)
+    }
+
+    /// Every entry with all its bookkeeping, expired ones included, in eviction order.
+    /// Meant for troubleshooting, e.g. a debug endpoint: it clones every entry under the locks.
+    pub fn debug_dump(&self) -> Vec<EntryDebug<K, V>> {
+        let expiry = self.liveness();
+        let now = self.clock.now();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        self.in_insertion_order(&insert_guard, &data_guard)
+            .enumerate()
+            .map(|(position, (k, e))| {
+                let expired = !e.is_live(expiry);
+                EntryDebug {
+                    key: k.clone(),
+                    value: e.value.clone(),
+                    age: now.since(e.written),
+                    remaining: if expired {
+                        Some(Duration::ZERO)
+                    } else {
+                        e.remaining(expiry)
+                    },
+                    expired,
+                    position,
+                }
+            })
+            .collect()
+    }
+
+    /// Every entry, expired or not, oldest first. Without order tracking the entries
+    /// are sorted by their sequence number instead.
+    fn in_insertion_order<'a>(
+        &self,
+        insert_guard: &'a BTreeMap<u64, K>,
+        data_guard: &'a BTreeMap<K, Entry<V>>,
+    ) -> impl DoubleEndedIterator<Item = (&'a K, &'a Entry<V>)> {
+        let entries: Vec<_> = if self.track_order {
+            insert_guard
+                .values()
+                .filter_map(|k| data_guard.get_key_value(k))
+                .collect()
+        } else {
+            let mut entries: Vec<_> = data_guard.iter().collect();
+            entries.sort_by_key(|(_, e)| e.seq);
+            entries
+        };
+        entries.into_iter()
+    }
+
+    /// Gives every live entry `by` more time to live, e.g. to keep everything around during
+    /// a maintenance window. The extension goes into the entry's own time-to-live, so an entry
+    /// that followed the cache-wide one keeps the extended value even if the cache's TTL changes
+    /// later. Entries keep their age: idle expiry and `with_max_age` are not pushed back.
+    pub fn extend_ttl_all(&self, by: Duration) {
+        let expiry = self.liveness();
+        for e in self
+            .data
+            .write()
+            .or_recover(self.poison)
+            .values_mut()
+            .filter(|e| e.is_live(expiry))
+        {
+            e.ttl = e.ttl.or(expiry.expiry.ttl).map(|ttl| ttl + by);
+            e.until = e.until.map(|until| until + by);
+        }
+    }
+
+    /// Returns the live keys, in key order, that will expire within the given window,
+    /// e.g. to refresh them before they do. Keys that never expire are not included.
+    pub fn keys_expiring_within(&self, window: Duration) -> Vec<K> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .filter(|(_, e)| e.remaining(expiry).is_some_and(|left| left <= window))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// The shortest time any live entry has left before it expires, e.g. for dashboards
+    /// showing the spread of expirations. Entries that never expire are left out.
+    pub fn min_ttl_remaining(&self) -> Option<Duration> {
+        self.ttl_remaining_range().map(|(min, _)| min)
+    }
+
+    /// The longest time any live entry has left before it expires, see `min_ttl_remaining`.
+    pub fn max_ttl_remaining(&self) -> Option<Duration> {
+        self.ttl_remaining_range().map(|(_, max)| max)
+    }
+
+    /// Counts the live entries by the time they have left, to see where expirations
+    /// cluster. `buckets` are ascending upper edges: the count at `i` is of entries with
+    /// at most `buckets[i]` left and more than `buckets[i - 1]`, and one extra count at
+    /// the end holds those with more than the last edge. Entries that never expire are left out.
+    pub fn ttl_histogram(&self, buckets: &[Duration]) -> Vec<usize> {
+        let expiry = self.liveness();
+        let mut counts = vec![0; buckets.len() + 1];
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .filter_map(|e| e.remaining(expiry))
+            .for_each(|left| counts[buckets.partition_point(|&edge| edge < left)] += 1);
+        counts
+    }
+
+    /// The earliest instant at which a live entry will expire, or None if none will,
+    /// so a host runtime can schedule its own timer instead of polling the cache.
+    /// An entry that expires by going unread may be read in the meantime and live on.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.min_ttl_remaining().map(|left| now + left)
+    }
+
+    /// The min and max time left of the live entries that expire, in one pass.
+    fn ttl_remaining_range(&self) -> Option<(Duration, Duration)> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .filter_map(|e| e.remaining(expiry))
+            .fold(None, |range, left| match range {
+                Some((min, max)) => Some((left.min(min), left.max(max))),
+                None => Some((left, left)),
+            })
+    }
+
+    /// Counts the live entries whose key starts with the given prefix, e.g. every
+    /// `"user:123:*"` key. Only the range of keys sharing the prefix is scanned.
+    pub fn count_prefix(&self, prefix: &K) -> usize
+    where
+        K: Borrow<str>,
+    {
+        let expiry = self.liveness();
+        let prefix_str: &str = prefix.borrow();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .range::<K, _>(prefix..)
+            .take_while(|(k, _)| (*k).borrow().starts_with(prefix_str))
+            .filter(|(_, e)| e.is_live(expiry))
+            .count()
+    }
+
+    /// Removes every entry whose key starts with the given prefix, e.g. to invalidate
+    /// a whole `"user:123:*"` namespace, and returns how many live entries were removed.
+    /// Only the range of keys sharing the prefix is scanned.
+    pub fn remove_prefix(&self, prefix: &K) -> usize
+    where
+        K: Borrow<str>,
+    {
+        let expiry = self.liveness();
+        let prefix_str: &str = prefix.borrow();
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let matching: Vec<K> = data_guard
+            .range::<K, _>(prefix..)
+            .take_while(|(k, _)| (*k).borrow().starts_with(prefix_str))
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut removed = 0;
+        for k in matching {
+            if let Some(e) = data_guard.remove::<K>(&k) {
+                insert_order.remove(&e.seq);
+                removed += usize::from(e.is_live(expiry));
+            }
+        }
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        removed
+    }
+
+    /// Folds over the live entries whose keys fall in `range`, in key order, e.g. to sum
+    /// the values of a key range. Runs under a single read lock without collecting the
+    /// entries first, so `f` must not call back into the cache for writes.
+    pub fn fold_range<R, A>(&self, range: R, init: A, mut f: impl FnMut(A, &K, &V) -> A) -> A
+    where
+        R: RangeBounds<K>,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .range(range)
+            .filter(|(_, e)| e.is_live(expiry))
+            .fold(init, |acc, (k, e)| f(acc, k, &e.value))
+    }
+
+    /// Returns the live entry with the smallest key, according to the key's `Ord`.
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .find(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+    }
+
+    /// Returns the live entry with the largest key, according to the key's `Ord`.
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .rev()
+            .find(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| (k.clone(), e.value.clone()))
+    }
+
+    /// Return an iterator over all values in the cache.
+    /// This will exclude any values for which the key is past the time-to-live.
+    /// Like `keys`, the iterator knows its exact length.
+    pub fn values(&self) -> impl ExactSizeIterator<Item = V> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(_, e)| e.value.clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Iterates over the keys in the cache, including expired ones the cleanup thread has not
+    /// removed yet, and yields each key with its value. Expired values are reloaded with
+    /// `loader` and put back into the cache as the iteration reaches them, so this
+    /// writes to the cache while iterating.
+    pub fn refreshing_iter<'a>(
+        &'a self,
+        loader: impl Fn(&K) -> V + 'a,
+    ) -> impl Iterator<Item = (K, V)> + 'a {
+        let keys: Vec<K> = self
+            .data
+            .read()
+            .or_recover(self.poison)
+            .keys()
+            .cloned()
+            .collect();
+        keys.into_iter().map(move |k| {
+            let v = self.get_live(&k).unwrap_or_else(|| {
+                let v = loader(&k);
+                self.put(k.clone(), v.clone());
+                v
+            });
+            (k, v)
+        })
+    }
+
+    /// Returns every distinct live value once, in ascending order,
+    /// e.g. to build a reverse index from values to keys.
+    pub fn distinct_values(&self) -> Vec<V>
+    where
+        V: Ord,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .map(|e| &e.value)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The number of live entries in the cache.
+    /// Without any time-to-live this is O(1); with one, entries have to be scanned
+    /// to leave out the expired ones that have not been cleaned up yet.
+    pub fn len(&self) -> usize {
+        // Entries can only expire once the cleanup thread has been started
+        if self.cleanup_thread.lock().or_recover(self.poison).is_none() {
+            return self.len.load(Ordering::Relaxed);
+        }
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .count()
+    }
+
+    /// The number of entries in the cache in O(1), without scanning or taking the data lock.
+    /// Without any time-to-live this is exact. With one it also counts expired entries
+    /// the cleanup thread has not swept yet, so it overestimates by at most the number of
+    /// entries expiring within one cleanup interval (unboundedly once the cleanup thread
+    /// was cancelled). Expired entries are still never returned by reads.
+    pub fn approx_len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Whether the cache holds no live entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cumulative hit and miss counts of `get` since the cache
+    /// was created or the stats were last reset.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zeroes the hit and miss counters, e.g. at the start of a reporting interval.
+    pub fn reset_stats(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    /// The fraction of `get` calls that were hits, or 0.0 if there were no reads yet.
+    pub fn hit_rate(&self) -> f64 {
+        let Stats { hits, misses } = self.stats();
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+
+    /// The largest number of entries the cache has held at once since it was
+    /// created or the mark was last reset.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Resets the high-water mark to the current number of entries.
+    pub fn reset_high_water_mark(&self) {
+        self.high_water_mark
+            .store(self.len.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Clears data and insert_order but leaves config and the cleanup thread running,
+    /// so the cache is immediately reusable with the same max size and time-to-live.
+    pub fn reset(&self) {
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        data_guard.clear();
+        insert_order.clear();
+        self.len.store(0, Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+    }
+
+    /// Empties the cache in one go and returns every live entry it held, in key order.
+    /// Expired entries are dropped without being returned.
+    pub fn drain(&self) -> Vec<(K, V)> {
+        let expiry = self.liveness();
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let drained = std::mem::take(&mut *data_guard)
+            .into_iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, e)| (k, e.value))
+            .collect();
+        insert_order.clear();
+        self.len.store(0, Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        drained
+    }
+
+    /// Removes every live entry matching `pred` and returns them in key order, keeping
+    /// the rest, all in one pass under the write lock. `pred` must not call back into the cache.
+    /// Like with `remove`, the extracted entries are not flushed to the write-back callback.
+    pub fn extract_if<F>(&self, mut pred: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let expiry = self.liveness();
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let extracted: Vec<(K, V)> = data_guard
+            .extract_if(.., |k, e| e.is_live(expiry) && pred(k, &e.value))
+            .map(|(k, e)| {
+                insert_order.remove(&e.seq);
+                (k, e.value)
+            })
+            .collect();
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        if !extracted.is_empty() {
+            notify_room(&self.room, self.poison);
+        }
+        extracted
+    }
+
+    /// Builds a new unbounded cache without a time-to-live holding every live entry
+    /// of this cache with its value transformed by `f`. Relative insertion order is preserved,
+    /// but the insertion time of every entry in the new cache is reset to now.
+    pub fn map_values<W, F>(&self, f: F) -> Cache<K, W>
+    where
+        W: Clone,
+        F: Fn(&V) -> W,
+    {
+        let mapped = Cache::new();
+        let expiry = self.liveness();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        for (k, e) in self.in_insertion_order(&insert_guard, &data_guard) {
+            if e.is_live(expiry) {
+                mapped.put(k.clone(), f(&e.value));
+            }
+        }
+        mapped
+    }
+
+    /// Whether every one of `keys` is in the cache and not expired, checked under a single
+    /// read lock so the answer is consistent, and stopping at the first missing key.
+    /// Unlike `exists` the checks don't count as reads for idle expiry.
+    pub fn contains_all(&self, keys: &[K]) -> bool {
+        let expiry = self.liveness();
+        let data_guard = self.data.read().or_recover(self.poison);
+        keys.iter()
+            .all(|k| data_guard.get(k).is_some_and(|e| e.is_live(expiry)))
+    }
+
+    /// Whether any of `keys` is in the cache and not expired, see `contains_all`.
+    pub fn contains_any(&self, keys: &[K]) -> bool {
+        let expiry = self.liveness();
+        let data_guard = self.data.read().or_recover(self.poison);
+        keys.iter()
+            .any(|k| data_guard.get(k).is_some_and(|e| e.is_live(expiry)))
+    }
+
+    /// Checks for the presence of a key.
+    /// This method will return false for any key past its time-to-live.
+    /// Like `get`, the key may be given in any borrowed form of `K`.
+    pub fn exists<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .inspect(|e| e.touch(&self.clock))
+            .is_some()
+    }
+
+    /// Like `exists`, but the key also has to have been put less than `max_age` ago, for
+    /// presence checks stricter than the cache's own expiry. Doesn't count as an access.
+    pub fn contains_key_fresh(&self, key: &K, max_age: Duration) -> bool {
+        let expiry = self.liveness();
+        let now = self.clock.now();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .is_some_and(|e| e.is_live(expiry) && now.since(e.written) < max_age)
+    }
+
+    /// How many times `get` or `exists` found the given key since it was last put.
+    /// Returns None if the key does not exist or is past its time-to-live.
+    pub fn access_count(&self, key: &K) -> Option<u64> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .map(|e| e.accesses.load(Ordering::Relaxed))
+    }
+
+    /// Streams every live entry to `w` as newline-delimited JSON, oldest first, without
+    /// building an intermediate copy of the cache. The read lock is held while writing.
+    /// Use [`Cache::read_snapshot`] to load the entries back.
+    #[cfg(feature = "serde")]
+    pub fn write_snapshot<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        let expiry = self.liveness();
+        let insert_guard = self.insert_order.read().or_recover(self.poison);
+        let data_guard = self.data.read().or_recover(self.poison);
+        for (k, e) in self.in_insertion_order(&insert_guard, &data_guard) {
+            if e.is_live(expiry) {
+                serde_json::to_writer(&mut *w, &(k, &e.value))?;
+                w.write_all(b"\n")?;
+            }
+        }
+        w.flush()
+    }
+
+    /// Reads entries written by [`Cache::write_snapshot`] one at a time and puts
+    /// them into the cache in the order they were written.
+    #[cfg(feature = "serde")]
+    pub fn read_snapshot<R: std::io::Read>(&self, r: &mut R) -> std::io::Result<()>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+    {
+        for entry in serde_json::Deserializer::from_reader(r).into_iter::<(K, V)>() {
+            let (k, v) = entry?;
+            self.put(k, v);
+        }
+        Ok(())
+    }
+
+    /// Checks whether any live entry holds the given value.
+    /// This scans every entry, so it is O(n) in the size of the cache.
+    pub fn contains_value(&self, val: &V) -> bool
+    where
+        V: PartialEq,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .any(|e| e.is_live(expiry) && e.value == *val)
+    }
+
+    /// Remove a key from the cache. Returns Some(value) on a successful removal
+    /// and None if the given key does not exist in the cache.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let entry = data_guard.remove(key)?;
+        insert_order.remove(&entry.seq);
+        self.len.store(data_guard.len(), Ordering::Relaxed);
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.room, self.poison);
+        Some(entry.value)
+    }
+}
+
+/// Everything that may start the cleanup thread, which needs the keys and values to be
+/// `'static` and shareable across threads. A cache without any expiry needs neither.
+impl<K: Ord + Clone + Sync + Send + 'static, V: Clone + Sync + Send + 'static> Cache<K, V> {
+    /// Builds a cache from `builder`, runs `f` with it and stops the cache before
+    /// returning `f`'s result, so no cleanup thread outlives the call, e.g. in tests.
+    /// A panic in the cleanup thread comes back as an error instead of being
+    /// only printed, as it is when a cache is dropped.
+    pub fn scoped<R>(
+        builder: CacheBuilder<K, V>,
+        f: impl FnOnce(&Cache<K, V>) -> R,
+    ) -> Result<R, ScopeError> {
+        let cache = builder.build().map_err(ScopeError::Build)?;
+        let result = f(&cache);
+        cache
+            .stop_cleanup()
+            .map_err(|_| ScopeError::CleanupPanicked)?;
+        Ok(result)
+    }
+
+    /// Updates the current cache with a time-to-live (TTL) for all keys in the cache.
+    /// This will start a background thread that purges any keys past their TTL.
+    /// Additionally, setting a ttl means that all cache "read" operations (get, exists, key iteration)
+    /// will consider the TTL such that the reader will never see values that are expired,
+    /// regardless if they have been cleaned up or not.
+    /// A TTL of `Duration::ZERO` disables the cache: nothing is stored and no thread is started.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.update_expiry(|expiry| expiry.ttl = Some(ttl));
+        if !ttl.is_zero() {
+            self.start_cleanup();
+        }
+        self
+    }
+
+    /// Serves entries in two stages for `get_swr`: up to `soft` old they are fresh,
+    /// between `soft` and `hard` they are stale but still served while being refreshed
+    /// in the background, and past `hard` they expire like with `with_ttl(hard)`.
+    pub fn with_stale_while_revalidate(mut self, soft: Duration, hard: Duration) -> Self {
+        self.stale_after = Some(soft);
+        self.with_ttl(hard)
+    }
+
+    /// Like `with_ttl`, but the TTL is rounded up to whole seconds, e.g. for a TTL computed
+    /// from a fraction that shouldn't expire entries at odd sub-second instants.
+    /// Only the TTL is rounded: entries are stored and checked exactly as with `with_ttl`.
+    pub fn with_rounded_ttl(self, ttl: Duration) -> Self {
+        let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        self.with_ttl(Duration::from_secs(secs))
+    }
+
+    /// Expires entries a fixed time after they were written, however often they are read.
+    /// The same as `with_ttl`, named to pair with `with_time_to_idle`: set both and an
+    /// entry expires at whichever comes first.
+    pub fn with_time_to_live(self, ttl: Duration) -> Self {
+        self.with_ttl(ttl)
+    }
+
+    /// Expires entries once they go unread for `idle`, each read restarting the clock.
+    /// Combines with `with_time_to_live`, whichever fires first. Starts the cleanup thread.
+    pub fn with_time_to_idle(mut self, idle: Duration) -> Self {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.update_expiry(|expiry| expiry.idle = Some(idle));
+        self.start_cleanup();
+        self
+    }
+
+    /// Updates the current cache to expire entries after an absolute time-to-live,
+    /// after going unread for too long, or both, whichever comes first.
+    /// Like `with_ttl`, this starts the cleanup thread and reads never see expired entries.
+    pub fn with_expiry(mut self, expiry: Expiry) -> Self {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.update_expiry(|current| *current = expiry);
+        if expiry.ttl.is_some_and(|ttl| !ttl.is_zero())
+            || expiry.idle.is_some()
+            || expiry.max_age.is_some()
+        {
+            self.start_cleanup();
+        }
+        self
+    }
+
+    /// Never serves an entry older than `age`, whatever the time-to-live of the cache or
+    /// of the entry, as a safety net against serving ancient data. Also works without a TTL.
+    /// Like `with_ttl`, this starts the cleanup thread.
+    pub fn with_max_age(mut self, age: Duration) -> Self {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.update_expiry(|expiry| expiry.max_age = Some(age));
+        self.start_cleanup();
+        self
+    }
+
+    /// Starts the thread purging expired keys, unless it is already running or the cache
+    /// is disabled by a zero TTL, which never stores anything to purge.
+    fn start_cleanup(&self) {
+        if self.is_disabled() {
+            return;
+        }
+        let mut cleanup_guard = self.cleanup_thread.lock().or_recover(self.poison);
+        if cleanup_guard.is_some() {
+            return;
+        }
+        let stop_flag = self.stop.clone();
+        let cancel = self.cancel.clone();
+        let expiry = self.expiry.clone();
+        let data = self.data.clone();
+        let insert_order = self.insert_order.clone();
+        let write_back = self.write_back.clone();
+        let expired_senders = self.expired_senders.clone();
+        let len = self.len.clone();
+        let room = self.room.clone();
+        let interval = self.cleanup_interval;
+        let clock = self.clock.clone();
+        let lock_strategy = self.lock_strategy;
+        let poison = self.poison;
+        cleanup_guard.replace(thread::spawn(move || {
+            while !*stop_flag.read().or_recover(poison) && !cancel.is_cancelled() {
+                let expiry = Liveness {
+                    expiry: *expiry.lock().or_recover(poison),
+                    now: clock.now(),
+                };
+                // With reader priority, don't queue up behind long-held read locks, a waiting
+                // writer would block every new reader too. The sweep is retried on the next interval.
+                let guards = match lock_strategy {
+                    LockStrategy::ReaderPriority => try_write(&insert_order, poison)
+                        .and_then(|insert_guard| Some((insert_guard, try_write(&data, poison)?))),
+                    #[cfg(feature = "parking_lot")]
+                    LockStrategy::WriterPriority => Some((
+                        insert_order.write().or_recover(poison),
+                        data.write().or_recover(poison),
+                    )),
+                };
+                if let Some((mut insert_guard, mut data_guard)) = guards {
+                    let expired: Vec<_> = data_guard
+                        .extract_if(.., |_, e| !e.is_live(expiry))
+                        .collect();
+                    for (_, e) in expired.iter() {
+                        insert_guard.remove(&e.seq);
+                    }
+                    len.store(data_guard.len(), Ordering::Relaxed);
+                    drop(data_guard);
+                    drop(insert_guard);
+                    if !expired.is_empty() {
+                        notify_room(&room, poison);
+                        let mut senders = expired_senders.lock().or_recover(poison);
+                        // Receivers that were dropped are forgotten on the first failed send
+                        senders.retain(|send| expired.iter().all(|(k, e)| send(k, &e.value)));
+                    }
+                    flush_evicted(&write_back, &expired, poison);
+                }
+                cancel.sleep(interval);
+            }
+        }));
+    }
+
+    /// Gives keys starting with `prefix` their own time-to-live, e.g. shorter-lived
+    /// `"session:"` keys next to long-lived `"config:"` ones. The TTL is applied when a key
+    /// is written, unless it's given one with `put_with_ttl`; when several namespaces match
+    /// the longest prefix wins, and keys outside any namespace use the cache-wide TTL.
+    /// Like `put_with_ttl`, writing a namespaced key starts the cleanup thread.
+    pub fn with_namespace_ttl(mut self, prefix: K, ttl: Duration) -> Self
+    where
+        K: Borrow<str>,
+    {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.namespace_ttls.insert(prefix, ttl);
+        self.in_namespace = Some(|key, prefix| key.borrow().starts_with(prefix.borrow()));
+        self
+    }
+
+    /// Lets values set their own expiry through [`HasExpiry`]: when a value is written
+    /// without an explicit TTL, it expires at its `expires_at`, if it has one, instead of
+    /// with the namespace or cache-wide TTL.
+    /// Like `put_with_ttl`, writing such a value starts the cleanup thread.
+    pub fn with_value_expiry(mut self) -> Self
+    where
+        V: HasExpiry,
+    {
+        self.spawn_cleanup = Some(Self::start_cleanup);
+        self.value_ttl = Some(|v| {
+            v.expires_at()
+                .map(|at| at.saturating_duration_since(Instant::now()))
+        });
+        self
+    }
+
+    /// Returns a channel the cleanup thread sends every entry to as it expires, e.g. to use
+    /// the cache as a delay queue that hands out items at their deadline. Entries arrive
+    /// once per sweep, so up to a cleanup interval late, and within a sweep in key order.
+    /// Entries evicted for size or removed are not sent.
+    pub fn expired_receiver(&self) -> Receiver<(K, V)> {
+        let (tx, rx) = mpsc::channel();
+        self.expired_senders
+            .lock()
+            .or_recover(self.poison)
+            .push(Box::new(move |k: &K, v: &V| {
+                tx.send((k.clone(), v.clone())).is_ok()
+            }));
+        rx
+    }
+
+    /// Puts a value into the cache for a given key with its own time-to-live,
+    /// which takes precedence over the cache-wide one.
+    /// This starts the cleanup thread if the cache did not have one yet.
+    pub fn put_with_ttl(&self, key: K, val: V, ttl: Duration) -> Option<V> {
+        self.start_cleanup();
+        self.put_entry(key, val, Some(ttl), None, None)
+            .map_or(None, InsertOutcome::into_replaced)
+    }
+
+    /// Puts a value that expires at a wall-clock `deadline` rather than after a time-to-live,
+    /// e.g. to expire everything at the top of the minute. The entry expires once
+    /// `SystemTime::now()` passes `deadline`, so unlike TTLs, which count on the monotonic
+    /// clock, it follows adjustments of the system clock: setting the clock forward
+    /// expires it early, and setting it back keeps it around longer.
+    /// This starts the cleanup thread if the cache did not have one yet.
+    pub fn put_until(&self, key: K, val: V, deadline: SystemTime) -> Option<V> {
+        self.start_cleanup();
+        self.put_entry(key, val, None, Some(deadline), None)
+            .map_or(None, InsertOutcome::into_replaced)
+    }
+
+    /// Gets a value like `get`, but once it is older than the soft limit set with
+    /// `with_stale_while_revalidate`, `refresh` is run on a background thread to reload it
+    /// while the stale value is still returned. Only one refresh runs per key at a time.
+    pub fn get_swr<F>(self: &Arc<Self>, key: &K, refresh: F) -> Option<V>
+    where
+        F: FnOnce(&K) -> V + Send + 'static,
+    {
+        let expiry = self.liveness();
+        let found = self
+            .data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .map(|e| {
+                e.touch(&self.clock);
+                let stale = self
+                    .stale_after
+                    .is_some_and(|soft| self.clock.now().since(e.written) >= soft);
+                (e.value.clone(), stale)
+            });
+        self.record_read(found.is_some());
+        let (val, stale) = found?;
+        if stale
+            && self
+                .refreshing
+                .lock()
+                .or_recover(self.poison)
+                .insert(key.clone())
+        {
+            let cache = Arc::clone(self);
+            let key = key.clone();
+            thread::spawn(move || {
+                // A panicking `refresh` mustn't leave the key marked as refreshing for good
+                let refreshed =
+                    panic::catch_unwind(AssertUnwindSafe(|| cache.put(key.clone(), refresh(&key))));
+                cache
+                    .refreshing
+                    .lock()
+                    .or_recover(cache.poison)
+                    .remove(&key);
+                if let Err(payload) = refreshed {
+                    panic::resume_unwind(payload);
+                }
+            });
+        }
+        Some(val)
+    }
+
+    /// Like `get_or_insert_with`, but a computed value is inserted with its own
+    /// time-to-live, as with `put_with_ttl`, instead of the cache-wide one.
+    pub fn get_or_insert_with_ttl(&self, key: K, ttl: Duration, f: impl FnOnce() -> V) -> V {
+        self.start_cleanup();
+        self.get_or_load(key, f, Some(ttl))
+    }
+}
+
+/// A cache of shared values: reads return an `Arc` pointing at the stored value
+/// instead of a clone of it, so `V` doesn't need to be `Clone`.
+pub type ArcCache<K, V> = Cache<K, Arc<V>>;
+
+impl<K: Ord + Clone, V> Cache<K, Arc<V>> {
+    /// Wraps `val` in an `Arc` and inserts it, returning the previous value if any.
+    pub fn put_shared(&self, key: K, val: V) -> Option<Arc<V>> {
+        self.put(key, Arc::new(val))
+    }
+
+    /// Inserts a value that is already shared, storing the `Arc` as it is instead of
+    /// wrapping the value again. Returns the previous `Arc` if any.
+    pub fn put_arc(&self, key: K, val: Arc<V>) -> Option<Arc<V>> {
+        self.put(key, val)
+    }
+}
+
+/// A cache that keeps metadata of type `M` next to each value, e.g. a content type
+/// or where the value came from. The pair expires and is evicted together.
+pub type MetaCache<K, V, M> = Cache<K, (V, M)>;
+
+impl<K: Ord + Clone, V: Clone, M: Clone> Cache<K, (V, M)> {
+    /// Inserts a value along with its metadata, returning the previous pair if any.
+    pub fn put_with_meta(&self, key: K, val: V, meta: M) -> Option<(V, M)> {
+        self.put(key, (val, meta))
+    }
+
+    /// Gets the value for the given key along with its metadata, like `get`.
+    pub fn get_with_meta<Q>(&self, key: &Q) -> Option<(V, M)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key)
+    }
+}
+
+impl<K: Ord + Clone> Cache<K, i64> {
+    /// Adds `delta` to the counter at `key` and returns the new count. A missing or
+    /// expired key counts from 0. Overflowing `i64` panics, in release builds too,
+    /// rather than silently wrapping; use `incr_saturating` or `incr_wrapping` for
+    /// counters that may get that far. A new key that doesn't fit in a full cache under
+    /// `FullPolicy::RejectNew` isn't stored, and the count it would have had is returned.
+    /// The same goes for a count the cache's validator refuses.
+    pub fn incr(&self, key: K, delta: i64) -> i64 {
+        self.incr_with(key, delta, i64::checked_add)
+            .expect("counter overflowed i64")
+    }
+
+    /// Like `incr`, but a count that would overflow stops at `i64::MAX` (or `i64::MIN`).
+    pub fn incr_saturating(&self, key: K, delta: i64) -> i64 {
+        self.incr_with(key, delta, |n, delta| Some(n.saturating_add(delta)))
+            .unwrap_or_default()
+    }
+
+    /// Like `incr`, but a count that would overflow wraps around past `i64::MAX`.
+    pub fn incr_wrapping(&self, key: K, delta: i64) -> i64 {
+        self.incr_with(key, delta, |n, delta| Some(n.wrapping_add(delta)))
+            .unwrap_or_default()
+    }
+
+    /// Reads and replaces the count under one write lock, so concurrent increments
+    /// aren't lost. Returns None, leaving the count as it was, if `add` overflows;
+    /// the caller panics once the locks are released so they aren't poisoned.
+    fn incr_with(&self, key: K, delta: i64, add: fn(i64, i64) -> Option<i64>) -> Option<i64> {
+        let expiry = self.liveness();
+        let max = *self.max_keys.lock().or_recover(self.poison);
+        let mut insert_guard = self.order_write();
+        let mut untracked = BTreeMap::new();
+        let insert_order = insert_guard.as_deref_mut().unwrap_or(&mut untracked);
+        let mut data_guard = self.data.write().or_recover(self.poison);
+        let count = data_guard
+            .get(&key)
+            .filter(|e| e.is_live(expiry))
+            .map_or(0, |e| e.value);
+        let count = add(count, delta)?;
+        if self.is_disabled() || !self.is_valid(&count) {
+            return Some(count);
+        }
+        // Under `FullPolicy::RejectNew` a new counter that doesn't fit isn't stored
+        let Some(evicted) = self.admit(insert_order, &mut data_guard, &key, max) else {
+            return Some(count);
+        };
+        let mut observed = Vec::new();
+        self.insert_entry(
+            insert_order,
+            &mut data_guard,
+            key,
+            count,
+            None,
+            &mut observed,
+        );
+        drop(data_guard);
+        drop(insert_guard);
+        notify_room(&self.arrivals, self.poison);
+        flush_evicted(&self.write_back, &evicted, self.poison);
+        self.notify_inserted(observed);
+        Some(count)
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_init() {
+        Cache::<String, i32>::new()
+            .with_max_size(15)
+            .with_ttl(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_cache_max_keys() {
+        let cache = Cache::<String, i32>::new().with_max_size(2);
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        cache.put("hello3".into(), 7);
+
+        assert_eq!(cache.keys().collect::<Vec<_>>().len(), 2);
+        assert!(!cache.exists("hello1"));
+    }
+
+    #[test]
+    fn test_cache_put_and_get() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        cache.put("hello3".into(), 7);
+
+        let expected: i32 = 5;
+        assert_eq!(
+            cache.get(&"hello1".to_string()).unwrap().to_owned(),
+            expected
+        );
+        assert_eq!(
+            cache.get(&"hello2".to_string()).unwrap().to_owned(),
+            expected + 1
+        );
+        assert_eq!(
+            cache.get(&"hello3".to_string()).unwrap().to_owned(),
+            expected + 2
+        );
+    }
+
+    #[test]
+    fn test_cache_remove() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 5);
+        cache.remove("hello1");
+
+        let expected: i32 = 5;
+        assert!(cache.get(&"hello1".to_string()).is_none());
+        assert_eq!(
+            cache.get(&"hello2".to_string()).unwrap().to_owned(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(15)
+            .with_ttl(Duration::from_millis(10));
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        cache.put("hello3".into(), 7);
+        thread::sleep(Duration::from_millis(10));
+        assert!(cache.keys().collect::<Vec<_>>().is_empty())
+    }
+
+    #[test]
+    fn test_cache_ttl_some_expired() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(15)
+            .with_ttl(Duration::from_millis(200));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(200));
+        cache.put("hello2".into(), 6);
+        cache.put("hello3".into(), 7);
+        cache.put("hello4".into(), 5);
+        assert!(cache.keys().collect::<Vec<_>>().len() == 3)
+    }
+
+    #[test]
+    fn test_cache_get_or_insert_with_single_flight() {
+        use std::sync::Barrier;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache: Arc<Cache<String, i32>> = Arc::new(Cache::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let calls = Arc::clone(&calls);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with("hello".into(), || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        42
+                    })
+                })
+            })
+            .collect();
+
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get("hello"), Some(42));
+    }
+
+    #[test]
+    fn test_cache_map_values() {
+        let cache: Cache<i32, i32> = Cache::new();
+        cache.put(3, 30);
+        cache.put(1, 10);
+        cache.put(2, 20);
+
+        let mapped: Cache<i32, String> = cache.map_values(|v| v.to_string());
+        assert_eq!(mapped.keys().collect::<Vec<_>>(), vec![1, 2, 3]);
+        for k in 1..=3 {
+            assert_eq!(mapped.get(&k), Some((k * 10).to_string()));
+        }
+        assert_eq!(
+            mapped
+                .insert_order
+                .read()
+                .unwrap()
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![3, 1, 2]
+        );
+
+        // The mapped values needn't be shareable across threads
+        let shared: Cache<i32, std::rc::Rc<i32>> = cache.map_values(|v| std::rc::Rc::new(*v));
+        assert_eq!(shared.get(&2).as_deref(), Some(&20));
+    }
+
+    #[test]
+    fn test_cache_max_keys_under_contention() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let max = 4;
+        let cache: Arc<Cache<usize, usize>> = Arc::new(Cache::new().with_max_size(max));
+        let done = Arc::new(AtomicBool::new(false));
+        let sampler = {
+            let cache = Arc::clone(&cache);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    assert!(cache.data.read().unwrap().len() <= max);
+                }
+            })
+        };
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        cache.put(t * 1000 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        sampler.join().unwrap();
+        assert_eq!(cache.keys().count(), max);
+    }
+
+    #[test]
+    fn test_cache_reset_keeps_cleanup_running() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(15)
+            .with_ttl(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+        cache.reset();
+        assert_eq!(cache.keys().count(), 0);
+
+        cache.put("hello2".into(), 6);
+        assert!(cache.exists("hello2"));
+        thread::sleep(Duration::from_millis(200));
+        // Expired entries are physically gone, so the cleanup thread is still sweeping
+        assert!(cache.data.read().unwrap().is_empty());
+        assert!(cache.insert_order.read().unwrap().is_empty());
+        assert!(cache.cleanup_thread.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cache_write_back_flushes_on_expiry() {
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&flushed);
+        let cache: Cache<String, i32> = Cache::new()
+            .with_write_back(move |k: &String, v: &i32| sink.lock().unwrap().push((k.clone(), *v)))
+            .with_ttl(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+        cache.put("hello1".into(), 6);
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(*flushed.lock().unwrap(), vec![("hello1".to_string(), 6)]);
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let cache: Cache<String, i32> = Cache::new();
+        assert_eq!(cache.hit_rate(), 0.0);
+        cache.put("hello1".into(), 5);
+        cache.get("hello1");
+        cache.get("hello1");
+        cache.get("hello1");
+        cache.get("hello2");
+
+        assert_eq!(cache.stats(), Stats { hits: 3, misses: 1 });
+        assert_eq!(cache.hit_rate(), 0.75);
+        cache.reset_stats();
+        assert_eq!(cache.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_cache_len_counter() {
+        let cache: Cache<i32, i32> = Cache::new().with_max_size(8);
+        let scanned = |cache: &Cache<i32, i32>| cache.data.read().unwrap().len();
+        for i in 0..10 {
+            cache.put(i, i);
+            assert_eq!(cache.len(), scanned(&cache));
+        }
+        for i in 0..5 {
+            cache.remove(&i);
+            assert_eq!(cache.len(), scanned(&cache));
+        }
+        assert_eq!(cache.len(), 5);
+        cache.reset();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_put_blocking() {
+        let cache: Arc<Cache<i32, i32>> = Arc::new(Cache::new().with_max_size(1));
+        cache.put(1, 1);
+        assert_eq!(
+            cache.put_blocking(2, 2, Duration::from_millis(20)),
+            Err(Timeout)
+        );
+
+        let producer = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || cache.put_blocking(2, 2, Duration::from_secs(5)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cache.exists(&2));
+        cache.remove(&1);
+
+        assert_eq!(producer.join().unwrap(), Ok(None));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_cache_contains_value() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+        assert!(cache.contains_value(&5));
+        assert!(!cache.contains_value(&6));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cache.contains_value(&5));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_cache_snapshot_round_trip() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello2".into(), 6);
+        cache.put("hello1".into(), 5);
+        let mut buf = Vec::new();
+        cache.write_snapshot(&mut buf).unwrap();
+
+        let restored: Cache<String, i32> = Cache::new();
+        restored.read_snapshot(&mut buf.as_slice()).unwrap();
+        assert_eq!(restored.get("hello1"), Some(5));
+        assert_eq!(restored.get("hello2"), Some(6));
+        assert_eq!(
+            restored
+                .insert_order
+                .read()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec!["hello2".to_string(), "hello1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_cache_insert_order_after_removals() {
+        let cache: Cache<i32, i32> = Cache::new();
+        for i in 0..1000 {
+            cache.put(i, i);
+        }
+        for i in (0..1000).filter(|i| i % 3 != 0) {
+            cache.remove(&i);
+        }
+        let order: Vec<_> = cache
+            .insert_order
+            .read()
+            .unwrap()
+            .values()
+            .copied()
+            .collect();
+        assert_eq!(order, (0..1000).step_by(3).collect::<Vec<_>>());
+        assert_eq!(order.len(), cache.len());
+    }
+
+    #[test]
+    fn test_cache_builder() {
+        let cache = Cache::<String, i32>::builder()
+            .max_size(100)
+            .ttl(Duration::from_millis(50))
+            .cleanup_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        cache.put("hello1".into(), 5);
+        assert_eq!(cache.get("hello1"), Some(5));
+        thread::sleep(Duration::from_millis(100));
+        assert!(cache.data.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_builder_validation() {
+        assert_eq!(
+            Cache::<String, i32>::builder().max_size(0).build().err(),
+            Some(BuildError::ZeroMaxSize)
+        );
+        assert_eq!(
+            Cache::<String, i32>::builder()
+                .cleanup_interval(Duration::ZERO)
+                .build()
+                .err(),
+            Some(BuildError::ZeroCleanupInterval)
+        );
+        assert_eq!(
+            Cache::<String, i32>::builder()
+                .ttl(Duration::from_millis(10))
+                .cleanup_interval(Duration::from_millis(100))
+                .build()
+                .err(),
+            Some(BuildError::CleanupIntervalExceedsTtl)
+        );
+    }
+
+    #[test]
+    fn test_cache_access_count() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        assert_eq!(cache.access_count(&"hello1".into()), Some(0));
+        for _ in 0..3 {
+            cache.get("hello1");
+        }
+        assert_eq!(cache.access_count(&"hello1".into()), Some(3));
+        assert!(cache.exists("hello1"));
+        assert_eq!(cache.access_count(&"hello1".into()), Some(4));
+        assert_eq!(cache.access_count(&"hello2".into()), None);
+    }
+
+    #[test]
+    fn test_cache_drain() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello2".into(), 6);
+        cache.put("hello1".into(), 5);
+
+        assert_eq!(
+            cache.drain(),
+            vec![("hello1".to_string(), 5), ("hello2".to_string(), 6)]
+        );
+        assert_eq!(cache.len(), 0);
+        assert!(cache.insert_order.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_reverse_key_order() {
+        use std::cmp::Reverse;
+
+        let cache: Cache<Reverse<i32>, i32> = Cache::new();
+        for i in [2, 5, 1] {
+            cache.put(Reverse(i), i);
+        }
+        assert_eq!(cache.first_key_value(), Some((Reverse(5), 5)));
+        assert_eq!(cache.last_key_value(), Some((Reverse(1), 1)));
+    }
+
+    #[test]
+    fn test_cache_from_maps() {
+        let btree = BTreeMap::from([(2, 20), (1, 10)]);
+        let cache: Cache<i32, i32> = btree.into();
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.get(&2), Some(20));
+        assert_eq!(
+            cache
+                .insert_order
+                .read()
+                .unwrap()
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let hash = HashMap::from([(3, 30), (4, 40)]);
+        let cache: Cache<i32, i32> = hash.into();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&3), Some(30));
+        assert_eq!(cache.get(&4), Some(40));
+    }
+
+    #[test]
+    fn test_cache_refresh_on_write() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(100));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(60));
+        cache.put("hello1".into(), 6);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("hello1"), Some(6));
+
+        let cache: Cache<String, i32> = Cache::new()
+            .with_refresh_on_write(false)
+            .with_ttl(Duration::from_millis(100));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(60));
+        cache.put("hello1".into(), 6);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("hello1"), None);
+    }
+
+    #[test]
+    fn test_cache_overwrites_do_not_grow_insert_order() {
+        let cache: Cache<String, i32> = Cache::new();
+        for i in 0..10_000 {
+            cache.put("hello1".into(), i);
+        }
+        assert_eq!(cache.insertion_order().len(), 1);
+        assert_eq!(cache.get("hello1"), Some(9_999));
+    }
+
+    #[test]
+    fn test_cache_get_pair() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(100));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(60));
+        cache.put("hello2".into(), 6);
+        assert_eq!(
+            cache.get_pair(&"hello1".into(), &"hello2".into()),
+            (Some(5), Some(6))
+        );
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            cache.get_pair(&"hello1".into(), &"hello2".into()),
+            (None, Some(6))
+        );
+        assert_eq!(cache.stats(), Stats { hits: 3, misses: 1 });
+    }
+
+    #[test]
+    fn test_cache_zero_ttl_disables_cache() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::ZERO);
+        assert!(cache.cleanup_thread.lock().unwrap().is_none());
+        cache.put("hello1".into(), 5);
+        assert_eq!(cache.get("hello1"), None);
+        assert!(!cache.exists("hello1"));
+        assert!(cache.data.read().unwrap().is_empty());
+
+        // Writes with their own expiry don't start the cleanup thread either
+        cache.put_with_ttl("hello1".into(), 5, Duration::from_secs(1));
+        cache.put_until(
+            "hello1".into(),
+            5,
+            SystemTime::now() + Duration::from_secs(1),
+        );
+        assert_eq!(
+            cache.get_or_insert_with_ttl("hello1".into(), Duration::from_secs(1), || 5),
+            5
+        );
+        assert!(cache.cleanup_thread.lock().unwrap().is_none());
+        assert!(cache.data.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_get_or_insert_with_ttl() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_secs(10));
+        let v = cache.get_or_insert_with_ttl("hello1".into(), Duration::from_millis(50), || 5);
+        assert_eq!(v, 5);
+        cache.put("hello2".into(), 6);
+        assert_eq!(cache.get("hello1"), Some(5));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("hello1"), None);
+        assert_eq!(cache.get("hello2"), Some(6));
+    }
+
+    #[test]
+    fn test_cache_put_with_ttl_starts_cleanup() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put_with_ttl("hello1".into(), 5, Duration::from_millis(20));
+        cache.put("hello2".into(), 6);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["hello2".to_string()]);
+        assert_eq!(cache.data.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cache_high_water_mark() {
+        let cache: Cache<i32, i32> = Cache::new();
+        for i in 0..5 {
+            cache.put(i, i);
+        }
+        for i in 0..4 {
+            cache.remove(&i);
+        }
+        assert_eq!(cache.high_water_mark(), 5);
+        cache.reset_high_water_mark();
+        assert_eq!(cache.high_water_mark(), 1);
+    }
+
+    #[test]
+    fn test_cache_expiry_idle_and_ttl() {
+        let cache: Cache<String, i32> = Cache::new().with_expiry(Expiry {
+            ttl: Some(Duration::from_millis(300)),
+            idle: Some(Duration::from_millis(100)),
+            ..Expiry::default()
+        });
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(cache.get("hello1"), Some(5));
+        }
+        // Never read, so it went idle long ago
+        assert!(!cache.exists("hello2"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("hello1"), None);
+    }
+
+    #[test]
+    fn test_cache_get_state() {
+        // A long cleanup interval keeps the expired entry around
+        let cache: Cache<String, i32> = Cache::new()
+            .with_cleanup_interval(Duration::from_secs(1))
+            .with_ttl(Duration::from_millis(20));
+        cache.put("hello1".into(), 5);
+        assert_eq!(cache.get_state(&"hello1".into()), GetState::Fresh(5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.get_state(&"hello1".into()), GetState::Expired);
+        assert_eq!(cache.get_state(&"hello2".into()), GetState::Absent);
+    }
+
+    #[test]
+    fn test_cache_no_deadlock_under_mixed_load() {
+        use std::sync::atomic::AtomicBool;
+
+        let cache: Arc<Cache<u32, u32>> = Cache::new()
+            .with_max_size(64)
+            .with_cleanup_interval(Duration::from_millis(1))
+            .with_ttl(Duration::from_millis(5))
+            .into_arc();
+        let done = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(AtomicU64::new(0));
+        let workers: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                let done = Arc::clone(&done);
+                let progress = Arc::clone(&progress);
+                thread::spawn(move || {
+                    let mut i = t;
+                    while !done.load(Ordering::SeqCst) {
+                        let key = i % 128;
+                        match i % 4 {
+                            0 => drop(cache.put(key, i)),
+                            1 => drop(cache.get(&key)),
+                            2 => drop(cache.remove(&key)),
+                            _ => drop(cache.keys().count()),
+                        }
+                        progress.fetch_add(1, Ordering::SeqCst);
+                        i = i.wrapping_mul(31).wrapping_add(7);
+                    }
+                })
+            })
+            .collect();
+
+        // Watchdog: every worker op bumps `progress`, so a stall means a deadlock
+        let start = Instant::now();
+        let mut last = 0;
+        while start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(250));
+            let now = progress.load(Ordering::SeqCst);
+            assert!(now > last, "cache operations stalled, likely a deadlock");
+            last = now;
+        }
+        done.store(true, Ordering::SeqCst);
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cache_get_into() {
+        let cache: Cache<String, Vec<u8>> = Cache::new();
+        let mut buf = Vec::with_capacity(64);
+        let ptr = buf.as_ptr();
+        for i in 0..10u8 {
+            cache.put("hello1".into(), vec![i; 16]);
+            assert!(cache.get_into(&"hello1".into(), &mut buf));
+            assert_eq!(buf, vec![i; 16]);
+        }
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(!cache.get_into(&"hello2".into(), &mut buf));
+        assert_eq!(buf, vec![9; 16]);
+    }
+
+    #[test]
+    fn test_cache_cleanup_skips_busy_lock() {
+        let cache: Arc<Cache<String, i32>> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(10))
+            .with_ttl(Duration::from_millis(20))
+            .into_arc();
+        cache.put("hello1".into(), 5);
+        let reader = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                let _guard = cache.data.read().unwrap();
+                thread::sleep(Duration::from_millis(300));
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        // A cleanup thread stuck waiting for the write lock would block this read
+        let start = Instant::now();
+        assert_eq!(cache.get("hello1"), None);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(cache.data.read().unwrap().len(), 1);
+
+        reader.join().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(cache.data.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_put_transaction_is_atomic() {
+        use std::sync::atomic::AtomicBool;
+
+        let cache: Arc<Cache<u32, u32>> = Cache::new().into_arc();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let cache = Arc::clone(&cache);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let seen = cache.keys().count();
+                    assert!(
+                        seen.is_multiple_of(10),
+                        "saw a partial batch of {seen} keys"
+                    );
+                }
+            })
+        };
+        for batch in 0..100 {
+            cache.put_transaction((0..10).map(|i| (batch * 10 + i, i)).collect());
+        }
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+        assert_eq!(cache.len(), 1000);
+    }
+
+    #[test]
+    fn test_cache_memoize() {
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let cache: Cache<u64, u64> = Cache::new();
+        let square = cache.memoize(move |n| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            n * n
+        });
+
+        assert_eq!(square(4), 16);
+        assert_eq!(square(4), 16);
+        assert_eq!(square(5), 25);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cache_rounded_ttl() {
+        let cache: Cache<String, i32> = Cache::new().with_rounded_ttl(Duration::from_millis(600));
+        cache.put("hello1".into(), 5);
+        // Still there past the 600ms given, since the TTL was rounded up to 1s
+        thread::sleep(Duration::from_millis(800));
+        assert_eq!(cache.get("hello1"), Some(5));
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(cache.get("hello1"), None);
+    }
+
+    #[test]
+    fn test_cache_keys_expiring_within() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_secs(1));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(300));
+        cache.put("hello2".into(), 6);
+        thread::sleep(Duration::from_millis(300));
+        cache.put("hello3".into(), 7);
+
+        // Remaining: hello1 ~400ms, hello2 ~700ms, hello3 ~1s
+        assert_eq!(
+            cache.keys_expiring_within(Duration::from_millis(550)),
+            vec!["hello1".to_string()]
+        );
+        assert_eq!(
+            cache.keys_expiring_within(Duration::from_millis(850)),
+            vec!["hello1".to_string(), "hello2".to_string()]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "parking_lot")]
+    fn test_cache_writer_priority_sweeps_under_constant_reads() {
+        use std::sync::atomic::AtomicBool;
+
+        let cache: Arc<Cache<u32, u32>> = Cache::new()
+            .with_lock_strategy(LockStrategy::WriterPriority)
+            .with_cleanup_interval(Duration::from_millis(10))
+            .with_ttl(Duration::from_millis(20))
+            .into_arc();
+        cache.put(1, 1);
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        let _guard = cache.data.read().unwrap();
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                })
+            })
+            .collect();
+
+        let start = Instant::now();
+        while !cache.data.read().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(1), "sweep starved");
+            thread::sleep(Duration::from_millis(5));
+        }
+        done.store(true, Ordering::SeqCst);
+        for r in readers {
+            r.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cache_count_prefix() {
+        let cache: Cache<String, i32> = Cache::new();
+        for key in [
+            "user:1:profile",
+            "user:2:profile",
+            "user:2:settings",
+            "session:1",
+            "session:2",
+            "users",
+        ] {
+            cache.put(key.into(), 0);
+        }
+        assert_eq!(cache.count_prefix(&"user:".into()), 3);
+        assert_eq!(cache.count_prefix(&"user:2:".into()), 2);
+        assert_eq!(cache.count_prefix(&"session:".into()), 2);
+        assert_eq!(cache.count_prefix(&"config:".into()), 0);
+    }
+
+    #[test]
+    fn test_cache_remove_prefix() {
+        let cache: Cache<String, i32> = Cache::new();
+        for key in [
+            "user:123:profile",
+            "user:123:settings",
+            "user:1234:profile",
+            "user:456:profile",
+            "session:123",
+        ] {
+            cache.put(key.into(), 0);
+        }
+        assert_eq!(cache.remove_prefix(&"user:123:".into()), 2);
+        assert_eq!(
+            cache.keys().collect::<Vec<_>>(),
+            vec![
+                "session:123".to_string(),
+                "user:1234:profile".to_string(),
+                "user:456:profile".to_string()
+            ]
+        );
+        assert_eq!(cache.insertion_order().len(), 3);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    // parking_lot's locks are never poisoned
+    #[cfg(not(feature = "parking_lot"))]
+    fn test_cache_poison_recover() {
+        let cache: Arc<Cache<String, i32>> = Cache::new()
+            .with_poison_policy(PoisonPolicy::Recover)
+            .into_arc();
+        cache.put("hello1".into(), 5);
+        let poisoner = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                let _guard = cache.data.write().unwrap();
+                panic!("poisoning the cache");
+            })
+        };
+        assert!(poisoner.join().is_err());
+        assert!(cache.data.is_poisoned());
+
+        assert_eq!(cache.get("hello1"), Some(5));
+        cache.put("hello2".into(), 6);
+        assert_eq!(cache.remove("hello1"), Some(5));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["hello2".to_string()]);
+    }
+
+    #[test]
+    fn test_cache_fold_range() {
+        let cache: Cache<i32, i32> = Cache::new().with_ttl(Duration::from_secs(60));
+        for key in 0..10 {
+            cache.put(key, key * 10);
+        }
+        cache.put_with_ttl(3, 30, Duration::from_millis(20));
+        assert_eq!(cache.fold_range(1..=5, 0, |sum, _, v| sum + v), 150);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.fold_range(1..=5, 0, |sum, _, v| sum + v), 120);
+        assert_eq!(cache.fold_range(20.., 0, |sum, _, v| sum + v), 0);
+    }
+
+    #[test]
+    fn test_cache_drop() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(20));
+        cache.put("hello1".into(), 5);
+        drop(cache);
+
+        // The last handle gets dropped on the cleanup thread, from the write-back
+        // callback, which must not try to join itself.
+        let slot = Arc::new(Mutex::new(None::<Arc<Cache<String, i32>>>));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cache = {
+            let slot = Arc::clone(&slot);
+            Cache::new()
+                .with_write_back(move |_: &String, _: &i32| {
+                    drop(slot.lock().unwrap().take());
+                    tx.send(thread::current().id()).unwrap();
+                })
+                .with_ttl(Duration::from_millis(20))
+                .into_arc()
+        };
+        cache.put("hello1".into(), 5);
+        slot.lock().unwrap().replace(cache);
+        let dropped_on = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_ne!(dropped_on, thread::current().id());
+        assert!(slot.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_get_or_insert_with_wakes_waiters_once() {
+        use std::sync::Barrier;
+
+        const WAITERS: usize = 32;
+        let cache: Arc<Cache<String, Arc<String>>> = Arc::new(Cache::new());
+        let barrier = Arc::new(Barrier::new(WAITERS));
+        let handles: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with("hello".into(), || {
+                        thread::sleep(Duration::from_millis(100));
+                        Arc::new("world".into())
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+        // Every caller read the cache exactly once, on its initial miss; nobody went
+        // back to the cache after being woken up.
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                hits: 0,
+                misses: WAITERS as u64
+            }
+        );
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_non_clone_values() {
+        #[derive(Debug)]
+        struct Handle(Mutex<Vec<u8>>);
+
+        let cache: ArcCache<String, Handle> = Cache::new().with_max_size(10);
+        assert!(
+            cache
+                .put_shared("log".into(), Handle(Mutex::new(vec![1])))
+                .is_none()
+        );
+        let first = cache.get("log").unwrap();
+        let second = cache.get("log").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        first.0.lock().unwrap().push(2);
+        assert_eq!(*second.0.lock().unwrap(), vec![1, 2]);
+
+        let previous = cache
+            .put_shared("log".into(), Handle(Mutex::new(vec![])))
+            .unwrap();
+        assert!(Arc::ptr_eq(&previous, &first));
+    }
+
+    #[test]
+    fn test_cache_set_max_size() {
+        let cache: Cache<i32, i32> = Cache::new().with_max_size(10);
+        for key in 0..10 {
+            cache.put(key, key);
+        }
+        cache.set_max_size(Some(4));
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.insertion_order(), vec![6, 7, 8, 9]);
+        cache.put(10, 10);
+        assert_eq!(cache.insertion_order(), vec![7, 8, 9, 10]);
+
+        cache.set_max_size(None);
+        for key in 11..20 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.len(), 13);
+    }
+
+    #[test]
+    fn test_cache_distinct_values() {
+        let cache: Cache<String, String> = Cache::new().with_ttl(Duration::from_secs(60));
+        for (user, team) in [
+            ("ann", "red"),
+            ("bob", "blue"),
+            ("cid", "red"),
+            ("dan", "red"),
+        ] {
+            cache.put(user.into(), team.into());
+        }
+        cache.put_with_ttl("eve".into(), "green".into(), Duration::from_millis(20));
+        assert_eq!(cache.distinct_values(), vec!["blue", "green", "red"]);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.distinct_values(), vec!["blue", "red"]);
+    }
+
+    #[test]
+    fn test_cache_wait_for_key() {
+        let cache: Arc<Cache<String, i32>> = Arc::new(Cache::new());
+        let waiter = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || cache.wait_for_key(&"hello1".into(), Duration::from_secs(5)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+        assert_eq!(waiter.join().unwrap(), Some(5));
+
+        let start = Instant::now();
+        assert_eq!(
+            cache.wait_for_key(&"hello2".into(), Duration::from_millis(50)),
+            None
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_cache_shrink_to() {
+        let cache: Cache<i32, i32> = Cache::new().with_max_size(10);
+        for key in 0..10 {
+            cache.put(key, key);
+        }
+        cache.shrink_to(3);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec![7, 8, 9]);
+        // The max size is unchanged
+        for key in 10..20 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.len(), 10);
+    }
+
+    #[test]
+    fn test_cache_refreshing_iter() {
+        let cache: Cache<i32, i32> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(300))
+            .with_ttl(Duration::from_secs(60));
+        for key in 0..6 {
+            if key % 2 == 0 {
+                cache.put(key, key);
+            } else {
+                cache.put_with_ttl(key, key, Duration::from_millis(20));
+            }
+        }
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.len(), 3);
+
+        let refreshed: Vec<_> = cache.refreshing_iter(|k| k * 100).collect();
+        assert_eq!(
+            refreshed,
+            vec![(0, 0), (1, 100), (2, 2), (3, 300), (4, 4), (5, 500)]
+        );
+        assert_eq!(cache.len(), 6);
+        assert_eq!(cache.get(&3), Some(300));
+    }
+
+    #[test]
+    fn test_cache_empty_like() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(3)
+            .with_ttl(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+
+        let fresh = cache.empty_like();
+        assert!(fresh.is_empty());
+        assert_eq!(fresh.max_size(), Some(3));
+        assert_eq!(fresh.ttl(), Some(Duration::from_millis(50)));
+        assert!(fresh.cleanup_thread.lock().unwrap().is_some());
+
+        fresh.put("hello2".into(), 6);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(fresh.len(), 0);
+        assert_eq!(Cache::<String, i32>::new().empty_like().ttl(), None);
+    }
+
+    #[test]
+    fn test_cache_namespace_ttl() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_namespace_ttl("session:".into(), Duration::from_millis(30))
+            .with_namespace_ttl("session:admin:".into(), Duration::from_secs(60))
+            .with_namespace_ttl("config:".into(), Duration::from_secs(60));
+        cache.put("session:1".into(), 1);
+        cache.put("session:admin:1".into(), 2);
+        cache.put("config:db".into(), 3);
+        cache.put("other".into(), 4);
+        assert_eq!(cache.len(), 4);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("session:1"), None);
+        assert_eq!(cache.get("session:admin:1"), Some(2));
+        assert_eq!(cache.get("config:db"), Some(3));
+        assert_eq!(cache.get("other"), Some(4));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_cache_checkpoint_restore() {
+        let cache: Cache<String, i32> = Cache::new().with_max_size(3);
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        let checkpoint = cache.checkpoint();
+
+        cache.put("hello1".into(), 50);
+        cache.remove("hello2");
+        cache.put("hello3".into(), 7);
+        cache.put("hello4".into(), 8);
+
+        cache.restore(checkpoint);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), Some(6));
+        assert_eq!(cache.get("hello3"), None);
+        assert_eq!(cache.insertion_order(), vec!["hello1", "hello2"]);
+        // The restored insert order still drives eviction
+        cache.put("hello3".into(), 7);
+        cache.put("hello4".into(), 8);
+        assert_eq!(cache.insertion_order(), vec!["hello2", "hello3", "hello4"]);
+    }
+
+    #[test]
+    fn test_cache_get_map() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        cache.put("hello3".into(), 7);
+        let keys: Vec<String> = (1..=5).map(|i| format!("hello{i}")).collect();
+        let found = cache.get_map(&keys);
+        assert_eq!(
+            found,
+            BTreeMap::from([("hello1".to_string(), 5), ("hello3".to_string(), 7)])
+        );
+        assert_eq!(cache.stats(), Stats { hits: 2, misses: 3 });
+
+        cache.reset_stats();
+        let keys = ["hello1", "hello1", "hello2"].map(String::from);
+        assert_eq!(cache.get_map(&keys).len(), 1);
+        assert_eq!(cache.stats(), Stats { hits: 2, misses: 1 });
+    }
+
+    #[test]
+    fn test_cache_warm_up() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(200));
+        let now = Instant::now();
+        cache.warm_up([
+            ("hello1".into(), 5, now - Duration::from_millis(170)),
+            ("hello2".into(), 6, now),
+        ]);
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.insertion_order(), vec!["hello1", "hello2"]);
+
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get("hello1"), None);
+        assert_eq!(cache.get("hello2"), Some(6));
+    }
+
+    #[test]
+    fn test_cache_max_age() {
+        let cache: Cache<String, i32> = Cache::new().with_max_age(Duration::from_millis(50));
+        cache.put("hello1".into(), 5);
+        cache.put_with_ttl("hello2".into(), 6, Duration::from_secs(60));
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), Some(6));
+
+        thread::sleep(Duration::from_millis(70));
+        assert_eq!(cache.get("hello1"), None);
+        assert_eq!(cache.get("hello2"), None);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.data.read().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_cache_expired_receiver() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(10))
+            .with_ttl(Duration::from_secs(60));
+        let expired = cache.expired_receiver();
+        cache.put_with_ttl("c".into(), 3, Duration::from_millis(150));
+        cache.put_with_ttl("a".into(), 1, Duration::from_millis(50));
+        cache.put_with_ttl("b".into(), 2, Duration::from_millis(100));
+        cache.put("d".into(), 4);
+
+        let arrived: Vec<_> = (0..3)
+            .map(|_| expired.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect();
+        assert_eq!(
+            arrived,
+            vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 3)]
+        );
+        assert!(expired.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_cache_cleanup_interval_clamped_to_ttl() {
+        let cache = Cache::<String, i32>::builder()
+            .ttl(Duration::from_millis(100))
+            .cleanup_interval(Duration::from_secs(10))
+            .strict(false)
+            .build()
+            .unwrap();
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(250));
+        assert!(cache.data.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_get_or_compute_batch() {
+        let cache: Cache<i32, i32> = Cache::new();
+        cache.put(1, 10);
+        cache.put(3, 30);
+        let mut asked = Vec::new();
+        let found = cache.get_or_compute_batch(&[1, 2, 3, 4, 2], |missing| {
+            asked.extend_from_slice(missing);
+            missing.iter().map(|k| (*k, k * 100)).collect()
+        });
+        assert_eq!(asked, vec![2, 4]);
+        assert_eq!(
+            found,
+            BTreeMap::from([(1, 10), (2, 200), (3, 30), (4, 400)])
+        );
+        assert_eq!(cache.get(&4), Some(400));
+
+        let found = cache.get_or_compute_batch(&[1, 2], |_| unreachable!());
+        assert_eq!(found, BTreeMap::from([(1, 10), (2, 200)]));
+    }
+
+    #[test]
+    fn test_cache_merge() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        let other: Cache<String, i32> = Cache::new();
+        other.put("hello2".into(), 60);
+        other.put("hello3".into(), 7);
+
+        cache.merge(&other, |mine, theirs| mine + theirs);
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), Some(66));
+        assert_eq!(cache.get("hello3"), Some(7));
+        assert_eq!(other.len(), 2);
+    }
+
+    #[test]
+    fn test_cache_borrowed_key_lookups() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 5);
+        let key: &str = "hello1";
+        assert_eq!(cache.get(key), Some(5));
+        assert!(cache.exists(key));
+        assert_eq!(cache.remove(key), Some(5));
+        assert!(!cache.exists(key));
+
+        let cache: Cache<Vec<u8>, i32> = Cache::new();
+        cache.put(vec![1, 2], 5);
+        assert_eq!(cache.get(&[1, 2][..]), Some(5));
+    }
+
+    #[test]
+    fn test_cache_stale_while_revalidate() {
+        let cache: Arc<Cache<String, i32>> = Cache::new()
+            .with_stale_while_revalidate(Duration::from_millis(50), Duration::from_millis(150))
+            .into_arc();
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        assert_eq!(cache.get_swr(&"hello1".into(), |_| unreachable!()), Some(5));
+
+        thread::sleep(Duration::from_millis(70));
+        // Stale: still served, and refreshed in the background
+        assert_eq!(cache.get_swr(&"hello1".into(), |_| 50), Some(5));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("hello1"), Some(50));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get_swr(&"hello2".into(), |_| 60), None);
+        assert_eq!(cache.get("hello1"), Some(50));
+    }
+
+    #[test]
+    fn test_cache_stale_while_revalidate_refresh_panics() {
+        let cache: Arc<Cache<String, i32>> = Cache::new()
+            .with_stale_while_revalidate(Duration::from_millis(50), Duration::from_millis(500))
+            .into_arc();
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(70));
+        assert_eq!(
+            cache.get_swr(&"hello1".into(), |_| panic!("backend down")),
+            Some(5)
+        );
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("hello1"), Some(5));
+
+        // The failed refresh doesn't block the next one
+        assert_eq!(cache.get_swr(&"hello1".into(), |_| 50), Some(5));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("hello1"), Some(50));
+    }
+
+    #[test]
+    fn test_cache_keys_values_exact_size() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_secs(60));
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        cache.put_with_ttl("hello3".into(), 7, Duration::from_millis(10));
+        assert_eq!(cache.keys().len(), 3);
+        thread::sleep(Duration::from_millis(30));
+
+        let mut values = cache.values();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values.size_hint(), (2, Some(2)));
+        values.next();
+        assert_eq!(values.len(), 1);
+        assert_eq!(cache.keys().len(), cache.len());
+    }
+
+    #[test]
+    fn test_cache_retain_recent() {
+        let cache: Cache<i32, i32> = Cache::new();
+        for key in [5, 3, 8, 1, 9, 0, 7, 2, 6, 4] {
+            cache.put(key, key);
+        }
+        cache.retain_recent(3);
+        assert_eq!(cache.insertion_order(), vec![2, 6, 4]);
+        assert_eq!(cache.len(), 3);
+        cache.retain_recent(10);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_cache_get_allow_stale() {
+        // A long cleanup interval keeps the expired entry around
+        let cache: Cache<String, i32> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(500))
+            .with_ttl(Duration::from_millis(20));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.get("hello1"), None);
+        assert_eq!(cache.get_allow_stale(&"hello1".into()), Some(5));
+        assert_eq!(cache.get_allow_stale(&"hello2".into()), None);
+    }
+
+    #[test]
+    fn test_cache_extract_if() {
+        let cache: Cache<String, i32> = Cache::new();
+        for (i, key) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            cache.put(key.into(), i as i32);
+        }
+        let evens = cache.extract_if(|_, v| v % 2 == 0);
+        assert_eq!(
+            evens,
+            vec![("a".into(), 0), ("c".into(), 2), ("e".into(), 4)]
+        );
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["b", "d"]);
+        assert_eq!(cache.insertion_order(), vec!["b", "d"]);
+        assert!(cache.extract_if(|_, v| v % 2 == 0).is_empty());
+    }
+
+    #[test]
+    fn test_cache_without_order_tracking() {
+        assert_eq!(
+            Cache::<String, i32>::builder()
+                .max_size(10)
+                .order_tracking(false)
+                .build()
+                .err(),
+            Some(BuildError::MaxSizeWithoutOrderTracking)
+        );
+
+        let cache = Cache::<String, i32>::builder()
+            .order_tracking(false)
+            .build()
+            .unwrap();
+        cache.put("hello2".into(), 6);
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 60);
+        assert!(cache.insert_order.read().unwrap().is_empty());
+        assert_eq!(cache.insertion_order(), vec!["hello2", "hello1"]);
+        assert_eq!(cache.remove("hello2"), Some(60));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.map_values(|v| v * 2).get("hello1"), Some(10));
+
+        // Writes never lock the untracked order, so holding it can't block them
+        let order = cache.insert_order.read().unwrap();
+        cache.put_transaction(vec![("hello3".into(), 7), ("hello4".into(), 8)]);
+        assert!(cache.put_versioned("hello5".into(), 9, 1));
+        cache.warm_up([("hello6".into(), 10, Instant::now())]);
+        assert_eq!(
+            cache.put_blocking("hello7".into(), 11, Duration::ZERO),
+            Ok(None)
+        );
+        assert_eq!(cache.remove("hello7"), Some(11));
+        assert_eq!(
+            cache.extract_if(|_, v| *v == 10),
+            vec![("hello6".into(), 10)]
+        );
+        assert_eq!(cache.remove_prefix(&"hello".into()), 4);
+        drop(order);
+    }
+
+    #[test]
+    fn test_cache_recent_and_oldest() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_secs(60));
+        cache.put("e".into(), 1);
+        cache.put("d".into(), 2);
+        cache.put("c".into(), 3);
+        cache.put_with_ttl("b".into(), 4, Duration::from_millis(10));
+        cache.put("a".into(), 5);
+        thread::sleep(Duration::from_millis(30));
+
+        assert_eq!(cache.recent(2), vec![("a".into(), 5), ("c".into(), 3)]);
+        assert_eq!(cache.oldest(2), vec![("e".into(), 1), ("d".into(), 2)]);
+        assert_eq!(cache.recent(10).len(), 4);
+    }
+
+    #[test]
+    fn test_cache_value_expiry() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Token {
+            exp: Option<Instant>,
+        }
+
+        impl HasExpiry for Token {
+            fn expires_at(&self) -> Option<Instant> {
+                self.exp
+            }
+        }
+
+        let cache: Cache<String, Token> = Cache::new().with_value_expiry();
+        let short = Token {
+            exp: Some(Instant::now() + Duration::from_millis(50)),
+        };
+        cache.put("short".into(), short.clone());
+        cache.put("forever".into(), Token { exp: None });
+        assert_eq!(cache.get("short"), Some(short));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cache.get("short"), None);
+        assert!(cache.exists("forever"));
+        assert_eq!(cache.data.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cache_entry_mut() {
+        let cache: Cache<String, Vec<i32>> = Cache::new().with_ttl(Duration::from_millis(100));
+        cache.put("hello1".into(), vec![1]);
+        thread::sleep(Duration::from_millis(50));
+        {
+            let mut entry = cache.entry_mut(&"hello1".into()).unwrap();
+            entry.push(2);
+            assert_eq!(entry.len(), 2);
+        }
+        assert_eq!(cache.get("hello1"), Some(vec![1, 2]));
+        assert!(cache.entry_mut(&"hello2".into()).is_none());
+
+        // The mutation did not reset the time-to-live
+        thread::sleep(Duration::from_millis(70));
+        assert_eq!(cache.get("hello1"), None);
+    }
+
+    #[test]
+    fn test_cache_extend_ttl_all() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(100));
+        cache.put("hello1".into(), 5);
+        cache.put_with_ttl("hello2".into(), 6, Duration::from_millis(80));
+        thread::sleep(Duration::from_millis(60));
+        cache.extend_ttl_all(Duration::from_millis(100));
+        assert!(cache.debug_dump()[0].age >= Duration::from_millis(60));
+
+        thread::sleep(Duration::from_millis(80));
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), Some(6));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("hello1"), None);
+        assert_eq!(cache.get("hello2"), None);
+    }
+
+    #[test]
+    fn test_cache_contains_key_fresh() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_secs(60));
+        cache.put("hello1".into(), 5);
+        assert!(cache.contains_key_fresh(&"hello1".into(), Duration::from_millis(30)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cache.contains_key_fresh(&"hello1".into(), Duration::from_millis(30)));
+        assert!(cache.contains_key_fresh(&"hello1".into(), Duration::from_secs(1)));
+        assert!(cache.exists("hello1"));
+        assert!(!cache.contains_key_fresh(&"hello2".into(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_cache_cancel_token() {
+        let token = CancelToken::new();
+        let cache: Cache<String, i32> = Cache::new()
+            .with_cancel(token.clone())
+            .with_cleanup_interval(Duration::from_secs(10))
+            .with_ttl(Duration::from_secs(10));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(20));
+
+        token.cancel();
+        assert!(token.is_cancelled());
+        let cleanup = cache.cleanup_thread.lock().unwrap().take().unwrap();
+        let start = Instant::now();
+        cleanup.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(cache.get("hello1"), Some(5));
+    }
+
+    #[test]
+    fn test_cache_approx_len() {
+        let cache: Cache<u32, u32> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(10))
+            .with_ttl(Duration::from_secs(60));
+        let expiring: Vec<_> = (0..10_000).map(|i| (i, i)).collect();
+        cache.put_transaction((10_000..100_000).map(|i| (i, i)).collect());
+        for (k, v) in expiring {
+            cache.put_with_ttl(k, v, Duration::from_millis(20));
+        }
+        assert!((90_000..=100_000).contains(&cache.approx_len()));
+
+        thread::sleep(Duration::from_millis(300));
+        let exact = cache.len();
+        assert_eq!(exact, 90_000);
+        assert!(cache.approx_len().abs_diff(exact) <= 100);
+    }
+
+    #[test]
+    fn test_cache_put_versioned() {
+        let cache: Cache<String, i32> = Cache::new();
+        assert!(cache.put_versioned("hello1".into(), 5, 5));
+        assert!(!cache.put_versioned("hello1".into(), 3, 3));
+        assert!(!cache.put_versioned("hello1".into(), 4, 5));
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert!(cache.put_versioned("hello1".into(), 7, 7));
+        assert_eq!(cache.get("hello1"), Some(7));
+    }
+
+    #[test]
+    fn test_cache_min_max_ttl_remaining() {
+        let cache: Cache<String, i32> = Cache::new();
+        assert_eq!(cache.min_ttl_remaining(), None);
+        cache.put("forever".into(), 0);
+        assert_eq!(cache.max_ttl_remaining(), None);
+
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(500));
+        cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(100));
+        cache.put("hello2".into(), 6);
+        let min = cache.min_ttl_remaining().unwrap();
+        let max = cache.max_ttl_remaining().unwrap();
+        assert!(min <= Duration::from_millis(400) && min > Duration::from_millis(300));
+        assert!(max <= Duration::from_millis(500) && max > Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_cache_full_policy_reject_new() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(2)
+            .with_full_policy(FullPolicy::RejectNew);
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+        assert_eq!(cache.put("hello3".into(), 7), None);
+        assert_eq!(cache.try_put("hello3".into(), 7), Err(Full));
+        assert!(!cache.exists("hello3"));
+
+        assert_eq!(cache.try_put("hello1".into(), 50), Ok(Some(5)));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["hello1", "hello2"]);
+
+        cache.remove("hello2");
+        assert_eq!(cache.try_put("hello3".into(), 7), Ok(None));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_cache_lock_timeout() {
+        let cache: Arc<Cache<String, i32>> = Cache::new()
+            .with_lock_timeout(Duration::from_millis(20))
+            .into_arc();
+        cache.put("hello1".into(), 5);
+        assert_eq!(cache.get_timed(&"hello1".into()), Ok(Some(5)));
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let holder = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                let _guard = cache.data.write().unwrap();
+                locked_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            })
+        };
+        locked_rx.recv().unwrap();
+        let start = Instant::now();
+        assert_eq!(cache.get_timed(&"hello1".into()), Err(Timeout));
+        assert_eq!(cache.put_timed("hello2".into(), 6), Err(Timeout));
+        assert!(start.elapsed() < Duration::from_millis(150));
+
+        holder.join().unwrap();
+        assert_eq!(cache.put_timed("hello2".into(), 6), Ok(None));
+        assert_eq!(cache.get_timed(&"hello2".into()), Ok(Some(6)));
     }
-}
 
-impl<K: Ord + Clone + Sync + Send + 'static, V: Clone + Sync + Send + 'static> Cache<K, V> {
-    /// A new Cache with the default setting: unbound size and no time-to-live.
-    pub fn new() -> Self {
-        Cache {
-            data: Arc::new(RwLock::new(BTreeMap::new())),
-            max_keys: Mutex::new(None),
-            ttl: Mutex::new(None),
-            cleanup_thread: Mutex::new(None),
-            stop: Arc::new(RwLock::new(false)),
-            insert_order: Arc::new(RwLock::new(VecDeque::new())),
-        }
+    #[test]
+    fn test_cache_get_or_insert_opt() {
+        let cache: Cache<String, i32> = Cache::new();
+        let calls = AtomicUsize::new(0);
+        let load = |found: bool| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            found.then_some(5)
+        };
+        assert_eq!(
+            cache.get_or_insert_opt("hello1".into(), || load(false)),
+            None
+        );
+        assert!(!cache.exists("hello1"));
+        assert_eq!(
+            cache.get_or_insert_opt("hello1".into(), || load(true)),
+            Some(5)
+        );
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(
+            cache.get_or_insert_opt("hello1".into(), || load(false)),
+            Some(5)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
     }
 
-    /// Updates the current cache with a given max_size that
-    /// will be considered when inserting new keys.
-    /// The cache will evict the "oldest" key in the cache once
-    /// it reaches its `max_size`
-    pub fn with_max_size(self, size: usize) -> Self {
-        *self.max_keys.lock().unwrap() = Some(size);
-        self
+    #[test]
+    fn test_cache_incr_reject_new() {
+        let cache: Cache<String, i64> = Cache::new()
+            .with_max_size(2)
+            .with_full_policy(FullPolicy::RejectNew);
+        cache.incr("hello1".into(), 1);
+        cache.incr("hello2".into(), 2);
+        assert_eq!(cache.incr("hello3".into(), 5), 5);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.exists("hello3"));
+        // Counters already in the cache still count up
+        assert_eq!(cache.incr("hello1".into(), 1), 2);
     }
 
-    /// Updates the current cache with a time-to-live (TTL) for all keys in the cache.
-    /// This will start a background thread that purges any keys past their TTL.
-    /// Additionally, setting a ttl means that all cache "read" operations (get, exists, key iteration)
-    /// will consider the TTL such that the reader will never see values that are expired,
-    /// regardless if they have been cleaned up or not.
-    pub fn with_ttl(self, ttl: Duration) -> Self {
-        self.ttl.lock().unwrap().replace(ttl);
-        let stop_flag = self.stop.clone();
-        let data = self.data.clone();
-        let insert_order = self.insert_order.clone();
-        self.cleanup_thread
-            .lock()
-            .unwrap()
-            .replace(thread::spawn(move || {
-                while !*stop_flag.read().unwrap() {
-                    let mut data_guard = data.write().unwrap();
-                    data_guard.retain(|_, (_, inst)| inst.elapsed() < ttl);
-                    drop(data_guard);
-                    let mut insert_guard = insert_order.write().unwrap();
-                    insert_guard.retain(|k| data.read().unwrap().contains_key(k));
-                    drop(insert_guard);
-                    thread::sleep(Duration::from_millis(50));
-                }
-            }));
-        self
+    #[test]
+    fn test_cache_incr_overflow() {
+        let cache: Cache<String, i64> = Cache::new();
+        assert_eq!(cache.incr("hello1".into(), 2), 2);
+        assert_eq!(cache.incr("hello1".into(), -5), -3);
+
+        cache.put("hello1".into(), i64::MAX - 1);
+        assert_eq!(cache.incr_saturating("hello1".into(), 5), i64::MAX);
+        assert_eq!(cache.incr_saturating("hello1".into(), 1), i64::MAX);
+        assert_eq!(cache.get("hello1"), Some(i64::MAX));
+
+        cache.put("hello1".into(), i64::MAX - 1);
+        assert_eq!(cache.incr_wrapping("hello1".into(), 2), i64::MIN);
+        assert_eq!(cache.get("hello1"), Some(i64::MIN));
+
+        cache.put("hello1".into(), i64::MAX);
+        let incr = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cache.incr("hello1".into(), 1)
+        }));
+        assert!(incr.is_err());
+        // The overflow leaves the count untouched and the cache usable
+        assert_eq!(cache.get("hello1"), Some(i64::MAX));
     }
 
-    /// Puts a value into the cache for a given key.
-    pub fn put(&self, key: K, val: V) -> Option<V> {
-        if let Some(max) = *self.max_keys.lock().unwrap()
-            && self.data.read().unwrap().len() >= max
-        {
-            // Yeet the oldest key
-            // In theory i shouldn't need to check this, since if there are
-            // any keys in the cache, let alone the max number of keys,
-            // there must be a value for the "oldest"
-            let oldest = self.insert_order.write().unwrap().pop_front();
-            match oldest {
-                Some(o) => self.data.write().unwrap().remove(&o),
-                None => None,
-            };
-        }
-        let inserted = self
-            .data
-            .write()
-            .unwrap()
-            .insert(key.clone(), (val, Instant::now()))
-            .map(|(v, _)| v);
-        self.insert_order.write().unwrap().push_back(key);
-        inserted
+    #[test]
+    fn test_cache_ttl_histogram() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put_with_ttl("hello1".into(), 1, Duration::from_secs(5));
+        cache.put_with_ttl("hello2".into(), 2, Duration::from_secs(30));
+        cache.put_with_ttl("hello3".into(), 3, Duration::from_secs(40));
+        cache.put_with_ttl("hello4".into(), 4, Duration::from_secs(600));
+        cache.put("hello5".into(), 5);
+
+        let buckets = [
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+            Duration::from_secs(300),
+        ];
+        assert_eq!(cache.ttl_histogram(&buckets), vec![1, 2, 0, 1]);
+        assert_eq!(cache.ttl_histogram(&[]), vec![4]);
     }
 
-    /// Gets the current value in the cache for the given key. Returns None if
-    /// the key does not exist or is past its time-to-live, if it has one.
-    pub fn get(&self, key: &K) -> Option<V> {
-        let c = self.data.read().unwrap();
-        if let Some((v, inst)) = c.get(key) {
-            if let Some(ttl) = *self.ttl.lock().unwrap() {
-                if inst.elapsed() < ttl {
-                    Some(v.clone())
-                } else {
-                    None
-                }
-            } else {
-                Some(v.clone())
-            }
-        } else {
-            None
+    #[test]
+    fn test_cache_requeue_on_overwrite() {
+        let cache: Cache<String, i32> = Cache::builder()
+            .max_size(4)
+            .requeue_on_overwrite(true)
+            .build()
+            .unwrap();
+        cache.put("hello1".into(), 1);
+        cache.put("hello2".into(), 2);
+        cache.put("hello3".into(), 3);
+        for i in 0..10_000 {
+            cache.put("hello1".into(), i);
         }
+        assert_eq!(cache.insert_order.read().unwrap().len(), 3);
+        assert_eq!(
+            cache.insertion_order(),
+            vec!["hello2".to_string(), "hello3".into(), "hello1".into()]
+        );
+        cache.put("hello4".into(), 4);
+        cache.put("hello5".into(), 5);
+        assert!(!cache.exists("hello2"));
+        assert!(cache.exists("hello3"));
+        assert_eq!(cache.get("hello1"), Some(9_999));
+
+        // By default an overwritten key keeps its place and goes first
+        let cache: Cache<String, i32> = Cache::new().with_max_size(3);
+        cache.put("hello1".into(), 1);
+        cache.put("hello2".into(), 2);
+        cache.put("hello1".into(), 3);
+        cache.put("hello3".into(), 4);
+        cache.put("hello4".into(), 5);
+        assert!(!cache.exists("hello1"));
+        assert!(cache.exists("hello2"));
     }
 
-    /// Return an iterator over all keys in the cache.
-    /// This will exclude any keys that are past the time-to-live.
-    pub fn keys(&self) -> impl Iterator<Item = K> {
-        let ttl = *self.ttl.lock().unwrap();
-        self.data
-            .read()
-            .unwrap()
-            .iter()
-            .filter_map(|(k, (_, inst))| {
-                if let Some(ttl) = ttl {
-                    if inst.elapsed() < ttl {
-                        Some(k.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    Some(k.clone())
-                }
+    #[test]
+    fn test_cache_on_insert_and_on_hit() {
+        let (insert_tx, insert_rx) = mpsc::channel();
+        let (hit_tx, hit_rx) = mpsc::channel();
+        let insert_tx = Mutex::new(insert_tx);
+        let hit_tx = Mutex::new(hit_tx);
+        let cache: Cache<String, i32> = Cache::new()
+            .with_on_insert(move |k: &String, v: &i32| {
+                insert_tx.lock().unwrap().send((k.clone(), *v)).unwrap()
             })
-            .collect::<Vec<_>>()
-            .into_iter()
+            .with_on_hit(move |k: &String, v: &i32| {
+                hit_tx.lock().unwrap().send((k.clone(), *v)).unwrap()
+            });
+        cache.put("hello1".into(), 5);
+        cache.put("hello1".into(), 6);
+        assert_eq!(cache.get("hello1"), Some(6));
+        assert_eq!(cache.get("hello2"), None);
+        cache.put_transaction(vec![("hello1".into(), 7), ("hello2".into(), 8)]);
+        cache.put_versioned("hello3".into(), 9, 1);
+        drop(cache);
+
+        assert_eq!(
+            insert_rx.iter().collect::<Vec<_>>(),
+            vec![
+                ("hello1".into(), 5),
+                ("hello2".into(), 8),
+                ("hello3".into(), 9)
+            ]
+        );
+        assert_eq!(
+            hit_rx.iter().collect::<Vec<_>>(),
+            vec![("hello1".into(), 6)]
+        );
     }
 
-    /// Return an iterator over all values in the cache.
-    /// This will exclude any values for which the key is past the time-to-live.
-    pub fn values(&self) -> impl Iterator<Item = V> {
-        let ttl = *self.ttl.lock().unwrap();
-        self.data
-            .read()
-            .unwrap()
-            .iter()
-            .filter_map(|(_, (v, inst))| {
-                if let Some(ttl) = ttl {
-                    if inst.elapsed() < ttl {
-                        Some(v.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    Some(v.clone())
-                }
-            })
-            .collect::<Vec<_>>()
-            .into_iter()
+    #[test]
+    fn test_cache_scoped() {
+        let builder = Cache::builder()
+            .ttl(Duration::from_millis(50))
+            .cleanup_interval(Duration::from_millis(10));
+        let (len, data) = Cache::scoped(builder, |cache: &Cache<String, i32>| {
+            cache.put("hello1".into(), 5);
+            assert!(cache.cleanup_thread.lock().unwrap().is_some());
+            (cache.len(), Arc::clone(&cache.data))
+        })
+        .unwrap();
+        assert_eq!(len, 1);
+        // The cleanup thread has exited and let go of its handle on the data
+        assert_eq!(Arc::strong_count(&data), 1);
+
+        let builder = Cache::<String, i32>::builder().max_size(0);
+        assert_eq!(
+            Cache::scoped(builder, |_| ()),
+            Err(ScopeError::Build(BuildError::ZeroMaxSize))
+        );
     }
 
-    /// Checks for the presence of a key.
-    /// This method will return false for any key past its time-to-live.
-    pub fn exists(&self, key: &K) -> bool {
-        let binding = self.data.read().unwrap();
-        let entry = binding.get(key);
-        if self.ttl.lock().unwrap().is_some() {
-            match entry {
-                Some((_, instant)) => instant.elapsed() < self.ttl.lock().unwrap().unwrap(),
-                None => false,
-            }
-        } else {
-            entry.is_some()
-        }
+    #[test]
+    fn test_cache_meta() {
+        let cache: MetaCache<String, i32, &str> = Cache::new().with_ttl(Duration::from_millis(100));
+        cache.put_with_meta("hello1".into(), 5, "application/json");
+        assert_eq!(cache.get_with_meta("hello1"), Some((5, "application/json")));
+        assert_eq!(
+            cache.put_with_meta("hello1".into(), 6, "text/plain"),
+            Some((5, "application/json"))
+        );
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cache.get_with_meta("hello1"), None);
     }
 
-    /// Remove a key from the cache. Returns Some(value) on a successful removal
-    /// and None if the given key does not exist in the cache.
-    pub fn remove(&self, key: &K) -> Option<V> {
-        let val = self.data.write().unwrap().remove(key).map(|(v, _)| v);
-        match val {
-            Some(v) => {
-                // This key should be here, but it's not a problem to be safe
-                let mut insert_guard = self.insert_order.write().unwrap();
-                // if let Some(index) = insert_guard.iter().position(|k| k == key) {
-                //     self.insert_order.write().unwrap().remove(index);
-                // }
-
-                // I do not understand why `remove` causes a deadlock but retain works
-                insert_guard.retain(|k| k != key);
-                Some(v)
-            }
-            None => None,
+    #[test]
+    fn test_cache_evict_ratio() {
+        let cache: Cache<i32, i32> = Cache::new().with_max_size(8);
+        for i in 0..8 {
+            cache.put(i, i);
+        }
+        cache.evict_ratio(0.5);
+        assert_eq!(cache.insertion_order(), vec![4, 5, 6, 7]);
+        cache.evict_ratio(0.0);
+        assert_eq!(cache.len(), 4);
+        cache.evict_ratio(2.0);
+        assert!(cache.is_empty());
+
+        // Expired entries are swept and don't count towards the fraction
+        let cache: Cache<i32, i32> = Cache::new()
+            .with_max_size(8)
+            .with_cleanup_interval(Duration::from_secs(60));
+        for i in 0..4 {
+            cache.put_with_ttl(i, i, Duration::from_millis(10));
+        }
+        for i in 4..8 {
+            cache.put(i, i);
         }
+        thread::sleep(Duration::from_millis(20));
+        cache.evict_ratio(0.5);
+        assert_eq!(cache.insertion_order(), vec![6, 7]);
+        assert_eq!(cache.data.read().unwrap().len(), 2);
     }
-}
 
-#[cfg(test)]
-mod cache_tests {
-    use super::*;
+    #[test]
+    fn test_cache_get_without_expiry() {
+        let cache: Cache<String, i32> = Cache::new();
+        assert!(!cache.expires.load(Ordering::Relaxed));
+        cache.put("hello1".into(), 5);
+        cache.put_with_ttl("hello2".into(), 6, Duration::from_millis(50));
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), Some(6));
+        thread::sleep(Duration::from_millis(100));
+        // Entries' own TTLs still apply without a cache-wide one
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert_eq!(cache.get("hello2"), None);
+
+        let cache = cache.with_ttl(Duration::from_millis(50));
+        assert!(cache.expires.load(Ordering::Relaxed));
+        cache.put("hello3".into(), 7);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("hello3"), None);
+        assert!(cache.empty_like().expires.load(Ordering::Relaxed));
+    }
 
     #[test]
-    fn test_cache_init() {
-        Cache::<String, i32>::new()
-            .with_max_size(15)
-            .with_ttl(Duration::from_secs(1));
+    fn test_cache_next_expiry() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 1);
+        assert_eq!(cache.next_expiry(), None);
+
+        let start = Instant::now();
+        cache.put_with_ttl("hello2".into(), 2, Duration::from_millis(500));
+        thread::sleep(Duration::from_millis(50));
+        cache.put_with_ttl("hello3".into(), 3, Duration::from_millis(200));
+        thread::sleep(Duration::from_millis(50));
+        cache.put_with_ttl("hello4".into(), 4, Duration::from_millis(400));
+
+        // hello3 was inserted around 50ms in and lives for 200ms
+        let deadline = cache.next_expiry().unwrap();
+        let expected = start + Duration::from_millis(250);
+        assert!(deadline >= expected && deadline < expected + Duration::from_millis(50));
     }
 
     #[test]
-    fn test_cache_max_keys() {
-        let cache = Cache::<String, i32>::new().with_max_size(2);
+    fn test_cache_skip_equal_writes() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_ttl(Duration::from_millis(150))
+            .with_skip_equal_writes(true);
         cache.put("hello1".into(), 5);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.put("hello1".into(), 5), Some(5));
+        thread::sleep(Duration::from_millis(100));
+        // The equal write didn't reset the TTL
+        assert_eq!(cache.get("hello1"), None);
+
+        cache.put("hello2".into(), 5);
+        thread::sleep(Duration::from_millis(100));
         cache.put("hello2".into(), 6);
-        cache.put("hello3".into(), 7);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get("hello2"), Some(6));
+    }
 
-        assert_eq!(cache.keys().collect::<Vec<_>>().len(), 2);
-        assert!(!cache.exists(&"hello1".into()));
+    #[test]
+    fn test_cache_put_arc() {
+        let cache: ArcCache<String, Vec<u8>> = Cache::new();
+        let first = Arc::new(vec![0; 1024]);
+        assert!(cache.put_arc("hello1".into(), Arc::clone(&first)).is_none());
+        assert!(Arc::ptr_eq(&cache.get("hello1").unwrap(), &first));
+
+        let previous = cache.put_arc("hello1".into(), Arc::new(vec![1; 1024]));
+        assert!(Arc::ptr_eq(&previous.unwrap(), &first));
+        assert_eq!(Arc::strong_count(&first), 1);
     }
 
     #[test]
-    fn test_cache_put_and_get() {
-        let cache: Cache<String, i32> = Cache::new();
+    fn test_cache_time_to_live_and_idle() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_time_to_live(Duration::from_millis(300))
+            .with_time_to_idle(Duration::from_millis(100));
         cache.put("hello1".into(), 5);
         cache.put("hello2".into(), 6);
-        cache.put("hello3".into(), 7);
+        // Keep hello1 active with reads, leave hello2 idle
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(cache.get("hello1"), Some(5));
+        }
+        assert_eq!(cache.get("hello2"), None);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.get("hello1"), Some(5));
+        thread::sleep(Duration::from_millis(100));
+        // Reads don't stretch the time-to-live
+        assert_eq!(cache.get("hello1"), None);
+    }
 
-        let expected: i32 = 5;
+    #[test]
+    fn test_cache_contains_all_and_any() {
+        let cache: Cache<String, i32> = Cache::new();
+        cache.put("hello1".into(), 1);
+        cache.put("hello2".into(), 2);
+        cache.put_with_ttl("hello3".into(), 3, Duration::from_millis(50));
+        let keys = ["hello1".to_string(), "hello2".into(), "hello3".into()];
+        assert!(cache.contains_all(&keys));
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!cache.contains_all(&keys));
+        assert!(cache.contains_any(&keys));
+        assert!(!cache.contains_any(&["hello3".into(), "hello4".into()]));
+        assert!(cache.contains_all(&[]));
+        assert!(!cache.contains_any(&[]));
+    }
+
+    #[test]
+    fn test_cache_clock_eviction() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(3)
+            .with_eviction_policy(EvictionPolicy::Clock);
+        cache.put("hello1".into(), 1);
+        cache.put("hello2".into(), 2);
+        cache.put("hello3".into(), 3);
+        assert_eq!(cache.get("hello1"), Some(1));
+
+        // hello1 was read, so it gets a second chance and hello2 goes instead
+        cache.put("hello4".into(), 4);
+        assert!(cache.exists("hello1"));
+        assert!(!cache.exists("hello2"));
         assert_eq!(
-            cache.get(&"hello1".to_string()).unwrap().to_owned(),
-            expected
+            cache.insertion_order(),
+            vec!["hello3".to_string(), "hello1".into(), "hello4".into()]
+        );
+
+        // The `exists` above referenced hello1 again, but hello3 is unreferenced
+        cache.put("hello5".into(), 5);
+        assert!(!cache.exists("hello3"));
+        assert!(cache.exists("hello1"));
+    }
+
+    #[test]
+    fn test_cache_debug_dump() {
+        // Sweep rarely enough that the expired entry is still there to dump
+        let cache: Cache<String, i32> = Cache::new().with_cleanup_interval(Duration::from_secs(60));
+        cache.put("hello2".into(), 2);
+        cache.put_with_ttl("hello1".into(), 1, Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(100));
+        cache.put_with_ttl("hello3".into(), 3, Duration::from_secs(60));
+
+        let dump = cache.debug_dump();
+        assert_eq!(
+            dump.iter()
+                .map(|d| (d.key.as_str(), d.value, d.position, d.expired))
+                .collect::<Vec<_>>(),
+            vec![
+                ("hello2", 2, 0, false),
+                ("hello1", 1, 1, true),
+                ("hello3", 3, 2, false)
+            ]
         );
+        assert!(dump[0].age >= Duration::from_millis(100));
+        assert!(dump[2].age < Duration::from_millis(100));
+        assert_eq!(dump[0].remaining, None);
+        assert_eq!(dump[1].remaining, Some(Duration::ZERO));
+        assert!(dump[2].remaining > Some(Duration::from_secs(59)));
+    }
+
+    #[test]
+    fn test_cache_insert_outcome() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(2)
+            .with_cleanup_interval(Duration::from_secs(60));
+        assert_eq!(cache.insert("hello1".into(), 1), InsertOutcome::New);
+        assert_eq!(cache.insert("hello1".into(), 2), InsertOutcome::Replaced(1));
+
+        cache.put_with_ttl("hello2".into(), 3, Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(100));
         assert_eq!(
-            cache.get(&"hello2".to_string()).unwrap().to_owned(),
-            expected + 1
+            cache.insert("hello2".into(), 4),
+            InsertOutcome::RefreshedExpired(3)
         );
+        // Overwrites in a full cache don't evict, not even the key itself
+        assert_eq!(cache.insert("hello1".into(), 5), InsertOutcome::Replaced(2));
+        assert_eq!(cache.put("hello2".into(), 6), Some(4));
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["hello1", "hello2"]);
+
         assert_eq!(
-            cache.get(&"hello3".to_string()).unwrap().to_owned(),
-            expected + 2
+            cache.insert("hello3".into(), 7),
+            InsertOutcome::Evicted {
+                replaced: None,
+                victim: Some(("hello1".into(), 5)),
+            }
         );
+
+        let cache: Cache<String, i32> = Cache::new()
+            .with_max_size(1)
+            .with_full_policy(FullPolicy::RejectNew);
+        cache.put("hello1".into(), 1);
+        assert_eq!(cache.insert("hello2".into(), 2), InsertOutcome::Rejected);
     }
 
     #[test]
-    fn test_cache_remove() {
-        let cache: Cache<String, i32> = Cache::new();
-        cache.put("hello1".into(), 5);
-        cache.put("hello2".into(), 5);
-        cache.remove(&"hello1".into());
+    fn test_cache_value_validator() {
+        let cache: Cache<String, String> =
+            Cache::new().with_value_validator(|v: &String| !v.is_empty());
+        assert_eq!(
+            cache.insert("hello1".into(), String::new()),
+            InsertOutcome::Rejected
+        );
+        cache.put("hello1".into(), String::new());
+        assert!(!cache.exists("hello1"));
+        assert_eq!(
+            cache.insert("hello1".into(), "world".into()),
+            InsertOutcome::New
+        );
+        assert_eq!(cache.get("hello1"), Some("world".into()));
 
-        let expected: i32 = 5;
-        assert!(cache.get(&"hello1".to_string()).is_none());
+        // A computed invalid value is returned but not cached
+        assert_eq!(cache.get_or_insert_with("hello2".into(), String::new), "");
+        assert!(!cache.exists("hello2"));
+        cache.put_transaction(vec![
+            ("hello2".into(), String::new()),
+            ("hello3".into(), "x".into()),
+        ]);
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["hello1", "hello3"]);
+
+        // A change in place that fails the validator is undone once the guard drops
+        cache.entry_mut(&"hello1".into()).unwrap().clear();
+        assert_eq!(cache.get("hello1"), Some("world".into()));
+        cache.entry_mut(&"hello1".into()).unwrap().push('!');
+        assert_eq!(cache.get("hello1"), Some("world!".into()));
+
+        // Counters are checked before they're stored
+        let counters: Cache<String, i64> = Cache::new().with_value_validator(|n: &i64| *n <= 10);
+        assert_eq!(counters.incr("hello1".into(), 8), 8);
+        assert_eq!(counters.incr("hello1".into(), 5), 13);
+        assert_eq!(counters.get("hello1"), Some(8));
+        assert_eq!(counters.incr("hello2".into(), 20), 20);
+        assert!(!counters.exists("hello2"));
+    }
+
+    #[test]
+    fn test_cache_iter_ordered() {
+        let cache: Cache<String, i32> = Cache::new().with_cleanup_interval(Duration::from_secs(60));
+        cache.put("c".into(), 1);
+        cache.put("a".into(), 2);
+        cache.put_with_ttl("d".into(), 3, Duration::from_millis(50));
+        cache.put("b".into(), 4);
+        thread::sleep(Duration::from_millis(100));
         assert_eq!(
-            cache.get(&"hello2".to_string()).unwrap().to_owned(),
-            expected
+            cache.iter_ordered(),
+            vec![("c".into(), 1), ("a".into(), 2), ("b".into(), 4)]
         );
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["a", "b", "c"]);
     }
 
     #[test]
-    fn test_cache_ttl() {
+    fn test_cache_put_until() {
+        let cache: Cache<String, i32> = Cache::new();
+        let deadline = SystemTime::now() + Duration::from_millis(100);
+        cache.put_until("hello1".into(), 5, deadline);
+        assert!(cache.cleanup_thread.lock().unwrap().is_some());
+        assert_eq!(cache.get("hello1"), Some(5));
+        assert!(cache.min_ttl_remaining() <= Some(Duration::from_millis(100)));
+
+        while SystemTime::now() < deadline - Duration::from_millis(20) {
+            assert_eq!(cache.get("hello1"), Some(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get("hello1"), None);
+    }
+
+    #[test]
+    fn test_cache_flush_dirty() {
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let sink = flushed.clone();
         let cache: Cache<String, i32> = Cache::new()
-            .with_max_size(15)
-            .with_ttl(Duration::from_millis(10));
-        cache.put("hello1".into(), 5);
-        cache.put("hello2".into(), 6);
-        cache.put("hello3".into(), 7);
-        thread::sleep(Duration::from_millis(10));
-        assert!(cache.keys().collect::<Vec<_>>().is_empty())
+            .with_write_back(move |k: &String, v: &i32| sink.lock().unwrap().push((k.clone(), *v)));
+        cache.warm_up([("hello0".to_string(), 0, Instant::now())]);
+        cache.put("hello1".into(), 1);
+        cache.put("hello2".into(), 2);
+        cache.put("hello1".into(), 3);
+        *cache.entry_mut(&"hello0".into()).unwrap() = 4;
+
+        cache.flush_dirty();
+        let mut once = flushed.lock().unwrap().clone();
+        once.sort();
+        assert_eq!(
+            once,
+            vec![
+                ("hello0".into(), 4),
+                ("hello1".into(), 3),
+                ("hello2".into(), 2)
+            ]
+        );
+        cache.flush_dirty();
+        assert_eq!(flushed.lock().unwrap().len(), 3);
+        assert_eq!(cache.len(), 3);
     }
 
     #[test]
-    fn test_cache_ttl_some_expired() {
+    fn test_cache_fallback() {
+        let shared: Arc<Cache<String, i32>> = Cache::new().into_arc();
+        shared.put("hello1".into(), 5);
+        let local: Cache<String, i32> = Cache::new().with_fallback(Arc::clone(&shared));
+
+        assert!(!local.exists("hello1"));
+        assert_eq!(local.get("hello1"), Some(5));
+        assert_eq!(local.stats(), Stats { hits: 0, misses: 1 });
+        // The value was promoted, so the next read hits locally
+        assert!(local.exists("hello1"));
+        assert_eq!(local.get("hello1"), Some(5));
+        assert_eq!(local.stats(), Stats { hits: 1, misses: 1 });
+        assert_eq!(local.get("hello2"), None);
+
+        let local: Cache<String, i32> = Cache::new()
+            .with_fallback(shared)
+            .with_fallback_promotion(false);
+        assert_eq!(local.get("hello1"), Some(5));
+        assert!(!local.exists("hello1"));
+    }
+
+    #[test]
+    fn test_cache_sample() {
+        let cache: Cache<i32, i32> = Cache::new().with_cleanup_interval(Duration::from_secs(60));
+        for i in 0..1_000 {
+            cache.put(i, i * 2);
+        }
+        cache.put_with_ttl(-1, 0, Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(50));
+
+        let sample = cache.sample(10);
+        assert_eq!(sample.len(), 10);
+        assert!(sample.iter().all(|(k, v)| cache.get(k) == Some(*v)));
+        let distinct: BTreeSet<_> = sample.iter().map(|(k, _)| k).collect();
+        assert_eq!(distinct.len(), 10);
+        // Not just the first keys in order
+        assert_ne!(cache.sample(10), cache.sample(10));
+
+        assert_eq!(cache.sample(5_000).len(), 1_000);
+        assert!(cache.sample(0).is_empty());
+    }
+
+    #[test]
+    fn test_cache_borrowed_values_without_ttl() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Cache<String, i32>>();
+        assert_send_sync::<Cache<&str, &[u8]>>();
+
+        let words = vec!["hello".to_string(), "world".to_string()];
+        let cache: Cache<&str, &String> = Cache::new().with_max_size(2);
+        for word in &words {
+            cache.put(&word[..1], word);
+        }
+        assert_eq!(cache.get("h"), Some(&words[0]));
+        assert_eq!(
+            cache.insert("x", &words[1]),
+            InsertOutcome::Evicted {
+                replaced: None,
+                victim: Some(("h", &words[0])),
+            }
+        );
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec!["w", "x"]);
+    }
+
+    #[test]
+    fn test_cache_expiry_after_cancel() {
+        let token = CancelToken::new();
         let cache: Cache<String, i32> = Cache::new()
-            .with_max_size(15)
-            .with_ttl(Duration::from_millis(200));
+            .with_cancel(token.clone())
+            .with_ttl(Duration::from_millis(600));
         cache.put("hello1".into(), 5);
-        thread::sleep(Duration::from_millis(200));
-        cache.put("hello2".into(), 6);
-        cache.put("hello3".into(), 7);
-        cache.put("hello4".into(), 5);
-        assert!(cache.keys().collect::<Vec<_>>().len() == 3)
+        token.cancel();
+        thread::sleep(Duration::from_millis(100));
+        assert!(
+            cache
+                .cleanup_thread
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .is_finished()
+        );
+        assert_eq!(cache.get("hello1"), Some(5));
+        // Nothing sweeps anymore, but reads still see the entry expire
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(cache.get("hello1"), None);
     }
 }
@@ -0,0 +1,46 @@
+//! The lock guarding a cache's entries and insert order. By default it is std's `RwLock`,
+//! whose priority between readers and writers is left to the operating system. With the
+//! `parking_lot` feature it is parking_lot's task-fair lock, where a waiting writer keeps
+//! new readers out, which `LockStrategy::WriterPriority` relies on.
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use std::sync::{
+    RwLock as CacheLock, RwLockReadGuard as CacheReadGuard, RwLockWriteGuard as CacheWriteGuard,
+};
+
+#[cfg(feature = "parking_lot")]
+pub(crate) use fair::{CacheLock, CacheReadGuard, CacheWriteGuard};
+
+#[cfg(feature = "parking_lot")]
+mod fair {
+    use std::sync::{LockResult, TryLockError, TryLockResult};
+
+    pub(crate) type CacheReadGuard<'a, T> = parking_lot::RwLockReadGuard<'a, T>;
+    pub(crate) type CacheWriteGuard<'a, T> = parking_lot::RwLockWriteGuard<'a, T>;
+
+    /// parking_lot's `RwLock` behind std's interface, so the cache's poison handling
+    /// applies unchanged. It is never poisoned.
+    pub(crate) struct CacheLock<T>(parking_lot::RwLock<T>);
+
+    impl<T> CacheLock<T> {
+        pub(crate) fn new(val: T) -> Self {
+            CacheLock(parking_lot::RwLock::new(val))
+        }
+
+        pub(crate) fn read(&self) -> LockResult<CacheReadGuard<'_, T>> {
+            Ok(self.0.read())
+        }
+
+        pub(crate) fn write(&self) -> LockResult<CacheWriteGuard<'_, T>> {
+            Ok(self.0.write())
+        }
+
+        pub(crate) fn try_read(&self) -> TryLockResult<CacheReadGuard<'_, T>> {
+            self.0.try_read().ok_or(TryLockError::WouldBlock)
+        }
+
+        pub(crate) fn try_write(&self) -> TryLockResult<CacheWriteGuard<'_, T>> {
+            self.0.try_write().ok_or(TryLockError::WouldBlock)
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, wrappers::UnboundedReceiverStream};
+
+use crate::{Cache, OrRecover};
+
+impl<K: Ord + Clone + Sync + Send + 'static, V: Clone + Sync + Send + 'static> Cache<K, V> {
+    /// The async counterpart of [`Cache::expired_receiver`]: a stream of every entry as
+    /// it expires, for tasks to `.next().await` on. Entries come in the same order and
+    /// with the same up-to-one-interval delay as on the channel.
+    pub fn expired_stream(&self) -> impl Stream<Item = (K, V)> + use<K, V> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.expired_senders
+            .lock()
+            .or_recover(self.poison)
+            .push(Box::new(move |k: &K, v: &V| {
+                tx.send((k.clone(), v.clone())).is_ok()
+            }));
+        UnboundedReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use std::time::{Duration, Instant};
+
+    use tokio_stream::StreamExt;
+
+    use crate::Cache;
+
+    #[tokio::test]
+    async fn test_cache_expired_stream() {
+        let cache: Cache<String, i32> = Cache::new()
+            .with_cleanup_interval(Duration::from_millis(10))
+            .with_ttl(Duration::from_secs(60));
+        let mut expired = cache.expired_stream();
+        let start = Instant::now();
+        cache.put_with_ttl("b".into(), 2, Duration::from_millis(100));
+        cache.put_with_ttl("a".into(), 1, Duration::from_millis(50));
+
+        assert_eq!(expired.next().await, Some(("a".into(), 1)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(expired.next().await, Some(("b".into(), 2)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
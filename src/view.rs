@@ -0,0 +1,178 @@
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    sync::{Arc, Mutex, atomic::AtomicBool},
+};
+
+use crate::{
+    Cache, Entry, EntryClock, Expiry, Liveness, OrRecover, PoisonPolicy, lock::CacheLock,
+    read_liveness,
+};
+
+/// A read-only handle on a [`Cache`], created with [`Cache::read_view`], for components
+/// that should only read from it. It shares the cache's storage, so it sees every write
+/// made through the cache, and is cheap to clone.
+///
+/// ```compile_fail
+/// use cream::Cache;
+///
+/// let cache: Cache<String, i32> = Cache::new();
+/// cache.read_view().put("hello".into(), 5);
+/// ```
+pub struct CacheView<K, V> {
+    data: Arc<CacheLock<BTreeMap<K, Entry<V>>>>,
+    expiry: Arc<Mutex<Expiry>>,
+    expires: Arc<AtomicBool>,
+    clock: Arc<EntryClock>,
+    poison: PoisonPolicy,
+}
+
+impl<K, V> Clone for CacheView<K, V> {
+    fn clone(&self) -> Self {
+        CacheView {
+            data: Arc::clone(&self.data),
+            expiry: Arc::clone(&self.expiry),
+            expires: Arc::clone(&self.expires),
+            clock: Arc::clone(&self.clock),
+            poison: self.poison,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Cache<K, V> {
+    /// A read-only handle on the cache, see [`CacheView`].
+    pub fn read_view(&self) -> CacheView<K, V> {
+        CacheView {
+            data: Arc::clone(&self.data),
+            expiry: Arc::clone(&self.expiry),
+            expires: Arc::clone(&self.expires),
+            clock: Arc::clone(&self.clock),
+            poison: self.poison,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> CacheView<K, V> {
+    fn liveness(&self) -> Liveness {
+        read_liveness(&self.expires, &self.expiry, &self.clock, self.poison)
+    }
+
+    /// Gets the current value for the given key, like [`Cache::get`].
+    /// Reads through a view don't count towards the cache's stats.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .map(|e| {
+                e.touch(&self.clock);
+                e.value.clone()
+            })
+    }
+
+    /// Whether the key is in the cache and not expired, like [`Cache::exists`].
+    pub fn exists<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .get(key)
+            .filter(|e| e.is_live(expiry))
+            .inspect(|e| e.touch(&self.clock))
+            .is_some()
+    }
+
+    /// The keys of the live entries, like [`Cache::keys`].
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = K> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .iter()
+            .filter(|(_, e)| e.is_live(expiry))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The values of the live entries, like [`Cache::values`].
+    pub fn values(&self) -> impl ExactSizeIterator<Item = V> {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .map(|e| e.value.clone())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The number of live entries, like [`Cache::len`].
+    pub fn len(&self) -> usize {
+        let expiry = self.liveness();
+        self.data
+            .read()
+            .or_recover(self.poison)
+            .values()
+            .filter(|e| e.is_live(expiry))
+            .count()
+    }
+
+    /// Whether there are no live entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod view_tests {
+    use std::{thread, time::Duration};
+
+    use crate::Cache;
+
+    #[test]
+    fn test_cache_read_view() {
+        let cache: Cache<String, i32> = Cache::new().with_ttl(Duration::from_millis(100));
+        let view = cache.read_view();
+        assert!(view.is_empty());
+        cache.put("hello1".into(), 5);
+        cache.put("hello2".into(), 6);
+
+        let view2 = view.clone();
+        assert_eq!(view2.get("hello1"), Some(5));
+        assert!(view.exists("hello2"));
+        assert_eq!(view.keys().collect::<Vec<_>>(), vec!["hello1", "hello2"]);
+        assert_eq!(view.values().collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!(view.len(), 2);
+        assert_eq!(cache.stats().hits, 0);
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(view.get("hello1"), None);
+        assert!(view.is_empty());
+    }
+
+    #[test]
+    fn test_cache_read_view_exists_is_a_read() {
+        let cache: Cache<String, i32> = Cache::new().with_time_to_idle(Duration::from_millis(100));
+        let view = cache.read_view();
+        cache.put("hello1".into(), 5);
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(60));
+            assert!(view.exists("hello1"));
+        }
+        // Each check kept the entry from going idle, like `Cache::exists` does
+        assert_eq!(cache.access_count(&"hello1".into()), Some(3));
+        assert_eq!(view.get("hello1"), Some(5));
+    }
+}